@@ -0,0 +1,6 @@
+CREATE TABLE tables (
+    id VARCHAR(32) NOT NULL PRIMARY KEY,
+    -- Full table state, as JSON
+    state LONGTEXT NOT NULL,
+    updated DATETIME NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE tables ADD archived BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,9 @@
+CREATE TABLE table_log (
+    table_id VARCHAR(32) NOT NULL,
+    seq BIGINT UNSIGNED NOT NULL,
+    -- Unix timestamp, in milliseconds
+    at BIGINT NOT NULL,
+    -- Log entry, as JSON
+    entry LONGTEXT NOT NULL,
+    PRIMARY KEY (table_id, seq)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE templates (
+    id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    -- UserId of the user that saved the template
+    owner VARCHAR(255) NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    -- Layout of the table, as JSON
+    state LONGTEXT NOT NULL,
+    created DATETIME NOT NULL,
+    INDEX (owner)
+);
@@ -0,0 +1,9 @@
+-- Tables each player has opened, for the "My Tables" page
+CREATE TABLE table_visits (
+    table_id VARCHAR(32) NOT NULL,
+    -- `user:<UserId>` or `guest:<id>`
+    player VARCHAR(255) NOT NULL,
+    visited DATETIME NOT NULL,
+    PRIMARY KEY (table_id, player),
+    INDEX (player, visited)
+);
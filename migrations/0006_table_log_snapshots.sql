@@ -0,0 +1,3 @@
+-- Snapshots are where replays start, so old entries can only be pruned up to one
+ALTER TABLE table_log ADD COLUMN snapshot BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE table_log SET snapshot = JSON_UNQUOTE(JSON_EXTRACT(entry, '$.event.e')) = 'snapshot';
@@ -106,6 +106,13 @@ pub struct UserInfo {
     email: String,
     role: Role,
 }
+
+impl UserInfo {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
 pub type User<'r> = rocket_auth::User<'r, DBConnInst>;
 pub type PUser<'r> = Option<rocket_auth::User<'r, DBConnInst>>;
 
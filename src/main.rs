@@ -32,6 +32,12 @@ pub struct Error {
 pub enum APIResponse<T> {
     #[response(status = 200, content_type = "json")]
     Ok(Json<T>),
+    #[response(status = 400, content_type = "json")]
+    BadRequest(Json<Error>),
+    #[response(status = 401, content_type = "json")]
+    Unauthorized(Json<Error>),
+    #[response(status = 403, content_type = "json")]
+    Forbidden(Json<Error>),
     #[response(status = 404, content_type = "json")]
     NotFound(Json<Error>),
     #[response(status = 500, content_type = "json")]
@@ -49,6 +55,18 @@ impl<T> APIResponse<T> {
         Self::Ok(Json(inner))
     }
 
+    pub fn bad_request(inner: Error) -> Self {
+        Self::BadRequest(Json(inner))
+    }
+
+    pub fn unauthorized(inner: Error) -> Self {
+        Self::Unauthorized(Json(inner))
+    }
+
+    pub fn forbidden(inner: Error) -> Self {
+        Self::Forbidden(Json(inner))
+    }
+
     pub fn not_found(inner: Error) -> Self {
         Self::NotFound(Json(inner))
     }
@@ -135,10 +153,8 @@ fn pages(page: Page, user: PUser<'_>) -> Template {
 async fn rocket() -> Result<(Rocket<Ignite>, impl Future<Output = ()>), rocket::Error> {
     let auth = AuthFairing::<DBConnInst>::fairing();
     let google_button = auth.google_button();
-    let (state, task) = GlobalState::new();
     let r = rocket::build()
         .attach(DBConn::init())
-        .manage(state)
         .attach(Template::custom(move |engines| {
             engines
                 .tera
@@ -154,6 +170,10 @@ async fn rocket() -> Result<(Rocket<Ignite>, impl Future<Output = ()>), rocket::
         .mount("/", routes![index, pages,])
         .ignite()
         .await?;
+    let task = r
+        .state::<GlobalState>()
+        .expect("Table state is managed by table::Routes")
+        .task();
     Ok((r, task))
 }
 
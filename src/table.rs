@@ -1,20 +1,21 @@
 use std::{
+    collections::HashMap,
     future::Future,
+    net::SocketAddr,
     sync::{
-        atomic::{AtomicU32, AtomicUsize},
-        Arc,
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize},
+        Arc, Mutex, RwLock,
     },
     time::Duration,
 };
 
 use chrono::Utc;
-use rand::Rng;
 use rocket::{
     fairing::{Fairing, Info, Kind},
     form::{Form, FromForm},
     get,
-    http::Status,
-    message, post,
+    http::{Cookie, CookieJar, Status},
+    join, leave, message, post,
     response::Redirect,
     routes,
     serde::json::Json,
@@ -22,15 +23,44 @@ use rocket::{
     Build, Rocket, State,
 };
 use rocket_auth::UserId;
+use rocket_db_pools::{sqlx::MySqlPool, Database};
 use rocket_dyn_templates::Template;
 use rocket::serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use crate::{
     account::{DBConnInst, PUser},
-    APIResponse, Error, TemplateCtx,
+    APIResponse, DBConn, Error, TemplateCtx,
 };
 
+mod card;
+mod chat;
+mod code;
+mod coalesce;
+mod deck;
+mod dice;
+mod drag;
+mod grab;
+mod hand;
+mod history;
+mod host;
+mod limits;
+mod lobby;
+mod my_tables;
+mod persist;
+mod player;
+mod pointer;
+mod presence;
+mod reject;
+mod replay;
+mod save;
+mod sync;
+mod template;
+mod throttle;
+
+pub use player::{Player, PlayerId};
+use reject::Reason;
+
 pub struct Routes;
 
 #[rocket::async_trait]
@@ -43,16 +73,65 @@ impl Fairing for Routes {
     }
 
     async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
-        Ok(rocket.mount(
+        // Every setting has a default, but any that are set have to be valid
+        let config: TableConfig = if rocket.figment().contains("tables") {
+            match rocket.figment().extract_inner("tables") {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("Invalid table config: {}", e);
+                    return Err(rocket);
+                }
+            }
+        } else {
+            TableConfig::default()
+        };
+        let db = match DBConn::fetch(&rocket) {
+            Some(db) => db.0.clone(),
+            None => return Err(rocket),
+        };
+        let state = match GlobalState::new(db, config).await {
+            Ok(state) => state,
+            Err(e) => {
+                println!("Failed to load tables: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(state).mount(
             "/",
             routes![
                 table,
+                join_table,
                 table_state,
                 handle_message,
+                ws_join,
+                ws_leave,
                 create,
                 create_table,
                 get_icon_pack,
                 find_table,
+                player::set_nickname,
+                host::kick,
+                host::lock,
+                host::listed,
+                host::transfer_host,
+                host::rename,
+                host::sharing,
+                host::whitelist,
+                host::invite,
+                host::uninvite,
+                replay::download_log,
+                replay::replay,
+                save::export_table,
+                save::import_table,
+                template::templates,
+                template::save_template,
+                template::delete_template,
+                my_tables::tables,
+                my_tables::tables_page,
+                my_tables::duplicate,
+                my_tables::delete_table,
+                lobby::lobby_tables,
+                lobby::lobby_page,
             ],
         ))
     }
@@ -64,6 +143,8 @@ struct CreateCtx {
     #[serde(flatten)]
     tem: TemplateCtx,
     item_packs: Vec<ItemPack>,
+    /// Templates the user has saved
+    templates: Vec<template::TemplateInfo>,
 }
 
 #[derive(Debug, Serialize)]
@@ -99,7 +180,11 @@ fn default_packs() -> Vec<ItemPack> {
 }
 
 #[get("/create")]
-fn create(user: PUser<'_>) -> Template {
+async fn create(user: PUser<'_>, player: Player, mut db: DBConnInst) -> Template {
+    let templates = match &player.id {
+        PlayerId::User(id) => template::list(id, &mut db).await.unwrap_or_default(),
+        PlayerId::Guest(_) => vec![],
+    };
     Template::render(
         "create",
         CreateCtx {
@@ -110,27 +195,132 @@ fn create(user: PUser<'_>) -> Template {
                 update_url: None,
             },
             item_packs: default_packs(),
+            templates,
         },
     )
 }
 
-#[get("/table/<id>")]
-fn table(id: &str, user: PUser<'_>, state: &State<GlobalState>) -> Result<Template, Status> {
-    {
-        let guard = state.map.guard();
-        if state.map.get(id, &guard).is_none() {
-            return Err(Status::NotFound);
-        }
-    }
-    Ok(Template::render(
-        "table",
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TableCtx {
+    #[serde(flatten)]
+    tem: TemplateCtx,
+    id: String,
+    /// Only needed once the player has joined the table
+    size: Option<limits::TableSize>,
+}
+
+fn join_page(id: &str, user: PUser<'_>, error: Option<&'static str>) -> Template {
+    Template::render(
+        "join",
+        TableCtx {
+            tem: TemplateCtx {
+                page: "join",
+                error,
+                user: user.map(|u| u.info().clone()),
+                update_url: None,
+            },
+            id: id.to_string(),
+            size: None,
+        },
+    )
+}
+
+fn find_page(user: PUser<'_>, error: &'static str) -> Template {
+    Template::render(
+        "find",
         TemplateCtx {
-            page: "table",
-            error: None,
+            page: "find",
+            error: Some(error),
             user: user.map(|u| u.info().clone()),
             update_url: None,
         },
-    ))
+    )
+}
+
+#[get("/table/<id>")]
+async fn table(
+    id: &str,
+    user: PUser<'_>,
+    player: Player,
+    cookies: &CookieJar<'_>,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> Result<Template, (Status, Template)> {
+    let id = &code::normalize(id);
+    let access = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) => t.check_access(id, &player, cookies),
+            None => return Err((Status::NotFound, find_page(user, "Table not found"))),
+        }
+    };
+    match access {
+        Ok(()) => {
+            if let Err(e) = my_tables::record_visit(&mut db, id, &player.id).await {
+                println!("Failed to record a visit to `{}`: {:?}", id, e);
+            }
+            Ok(Template::render(
+                "table",
+                TableCtx {
+                    tem: TemplateCtx {
+                        page: "table",
+                        error: None,
+                        user: user.map(|u| u.info().clone()),
+                        update_url: None,
+                    },
+                    id: id.to_string(),
+                    size: Some(state.config.size),
+                },
+            ))
+        }
+        Err(Denied::Password) => Err((Status::Unauthorized, join_page(id, user, None))),
+        Err(e) => Err((e.status(), find_page(user, e.message()))),
+    }
+}
+
+#[derive(FromForm)]
+struct JoinTable<'a> {
+    password: &'a str,
+}
+
+impl std::fmt::Debug for JoinTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoinTable")
+            .field("password", &"<hidden>")
+            .finish_non_exhaustive()
+    }
+}
+
+#[post("/table/<id>/join", data = "<data>")]
+fn join_table(
+    id: &str,
+    data: Form<JoinTable<'_>>,
+    user: PUser<'_>,
+    cookies: &CookieJar<'_>,
+    state: &State<GlobalState>,
+) -> Result<Redirect, (Status, Template)> {
+    let correct = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) => match t.sharing() {
+                SharingType::Password { password } => password == data.password,
+                _ => true,
+            },
+            None => return Err((Status::NotFound, find_page(user, "Table not found"))),
+        }
+    };
+    if correct {
+        // The cookie holds the password itself, so changing the password locks out everyone who
+        // joined with the old one
+        cookies.add_private(Cookie::new(password_cookie(id), data.password.to_string()));
+        Ok(Redirect::to(rocket::uri!(table(id = id))))
+    } else {
+        Err((
+            Status::Unauthorized,
+            join_page(id, user, Some("Incorrect password")),
+        ))
+    }
 }
 
 #[derive(Debug, FromForm)]
@@ -144,51 +334,101 @@ fn find_table(
     state: &State<GlobalState>,
     user: PUser<'_>,
 ) -> Result<Redirect, (Status, Template)> {
+    let code = code::normalize(data.code);
     {
         let guard = state.map.guard();
-        if state.map.get(data.code, &guard).is_none() {
-            return Err((
-                Status::BadRequest,
-                Template::render(
-                    "find",
-                    TemplateCtx {
-                        page: "find",
-                        error: Some("Table not found"),
-                        user: user.map(|u| u.info().clone()),
-                        update_url: None,
-                    },
-                ),
-            ));
+        if state.map.get(&code, &guard).is_none() {
+            return Err((Status::BadRequest, find_page(user, "Table not found")));
         }
     }
-    Ok(Redirect::to(rocket::uri!(table(id = data.code))))
+    Ok(Redirect::to(rocket::uri!(table(id = code))))
 }
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
 struct TableState {
     created: chrono::DateTime<Utc>,
-    name: String,
+    name: RwLock<String>,
     #[serde(skip)]
-    sharing: SharingType,
-    #[serde(skip)] // TODO: host
-    host: Option<UserId<'static>>,
+    sharing: RwLock<SharingType>,
+    #[serde(skip)]
+    host: RwLock<Option<UserId<'static>>>,
+    /// While locked, only the host can modify the table
+    locked: AtomicBool,
+    /// Whether the table is shown in the lobby, if it's public, see `lobby`
+    listed: AtomicBool,
+    /// Players the host has removed from the table
+    #[serde(skip)]
+    kicked: flurry::HashSet<PlayerId>,
+    /// Users allowed to join an invite only table, mapping `UserId`s to usernames
+    #[serde(skip)]
+    whitelist: flurry::HashMap<String, String>,
     #[serde(skip)]
     cur_id: AtomicU32,
     elements: flurry::HashMap<u32, ElementState>,
     icon_packs: flurry::HashSet<u32>,
+    /// Cards each player is holding, which only they can see
+    #[serde(skip)]
+    hands: flurry::HashMap<PlayerId, hand::Hand>,
+    #[serde(skip)]
+    history: Mutex<history::History>,
+    /// Log entries that haven't been saved yet
+    #[serde(skip)]
+    log: Mutex<Vec<replay::LogEntry>>,
+    /// Sequence number of the next log entry
+    #[serde(skip)]
+    log_seq: AtomicU64,
+    #[serde(skip)]
+    sequencer: Mutex<sync::Sequencer>,
+    /// Drag previews and moves of each element, see `drag`
+    #[serde(skip)]
+    drags: Mutex<HashMap<u32, coalesce::Tick>>,
+    /// Elements players are holding, see `grab`
+    #[serde(skip)]
+    grabs: Mutex<HashMap<u32, grab::Grab>>,
+    /// Recent chat messages
+    #[serde(skip)]
+    chat: Mutex<chat::Chat>,
+    /// When each player's cursor was last broadcast, and where it's moved since, see `pointer`
+    #[serde(skip)]
+    cursors: Mutex<HashMap<PlayerId, coalesce::Tick>>,
+    /// Players connected to the table, by connection, see `presence`
+    #[serde(skip)]
+    present: Mutex<HashMap<SocketAddr, Player>>,
+    /// Set whenever the table is modified, and cleared once it has been saved
+    #[serde(skip)]
+    dirty: AtomicBool,
+    /// Unix timestamp of the last time anyone looked at or modified the table
+    #[serde(skip)]
+    last_active: AtomicI64,
 }
 
 impl Clone for TableState {
     fn clone(&self) -> Self {
         Self {
             created: self.created.clone(),
-            name: self.name.clone(),
-            sharing: self.sharing.clone(),
-            host: self.host.as_ref().map(|u| u.clone()),
+            name: RwLock::new(self.name()),
+            sharing: RwLock::new(self.sharing()),
+            host: RwLock::new(self.host()),
+            locked: AtomicBool::new(self.is_locked()),
+            listed: AtomicBool::new(self.is_listed()),
+            kicked: self.kicked.clone(),
+            whitelist: self.whitelist.clone(),
             cur_id: AtomicU32::new(self.cur_id.load(std::sync::atomic::Ordering::Acquire)),
             elements: self.elements.clone(),
             icon_packs: self.icon_packs.clone(),
+            hands: self.hands.clone(),
+            history: Mutex::new(self.history.lock().unwrap().clone()),
+            log: Mutex::new(self.pending_log()),
+            log_seq: AtomicU64::new(self.log_seq.load(std::sync::atomic::Ordering::Acquire)),
+            sequencer: Mutex::new(self.sequencer.lock().unwrap().clone()),
+            drags: Mutex::default(),
+            grabs: Mutex::default(),
+            chat: Mutex::new(self.chat.lock().unwrap().clone()),
+            cursors: Mutex::default(),
+            present: Mutex::default(),
+            dirty: AtomicBool::new(self.dirty.load(std::sync::atomic::Ordering::Acquire)),
+            last_active: AtomicI64::new(self.last_active()),
         }
     }
 }
@@ -202,33 +442,195 @@ impl TableState {
     ) -> Self {
         Self {
             created: chrono::Utc::now(),
-            name,
-            sharing,
-            host,
+            name: RwLock::new(name),
+            sharing: RwLock::new(sharing),
+            host: RwLock::new(host),
+            locked: AtomicBool::new(false),
+            listed: AtomicBool::new(false),
+            kicked: flurry::HashSet::new(),
+            whitelist: flurry::HashMap::new(),
             cur_id: AtomicU32::new(1),
             elements: flurry::HashMap::new(),
             icon_packs,
+            hands: flurry::HashMap::new(),
+            history: Mutex::default(),
+            log: Mutex::default(),
+            log_seq: AtomicU64::new(0),
+            sequencer: Mutex::default(),
+            drags: Mutex::default(),
+            grabs: Mutex::default(),
+            chat: Mutex::default(),
+            cursors: Mutex::default(),
+            present: Mutex::default(),
+            dirty: AtomicBool::new(false),
+            last_active: AtomicI64::new(Utc::now().timestamp()),
+        }
+    }
+
+    /// Checks whether the user making a request is allowed to see and modify the table.
+    ///
+    /// The host can always access their own table.
+    fn check_access(
+        &self,
+        id: &str,
+        player: &Player,
+        cookies: &CookieJar<'_>,
+    ) -> Result<(), Denied> {
+        if self.is_host(player) {
+            return Ok(());
+        }
+        {
+            let guard = self.kicked.guard();
+            if self.kicked.contains(&player.id, &guard) {
+                return Err(Denied::Kicked);
+            }
+        }
+        match self.sharing() {
+            SharingType::Public {} => Ok(()),
+            SharingType::Password { password } => match cookies.get_private(&password_cookie(id)) {
+                Some(c) if c.value() == password => Ok(()),
+                _ => Err(Denied::Password),
+            },
+            SharingType::Whitelist { .. } => match &player.id {
+                PlayerId::User(id) => {
+                    let guard = self.whitelist.guard();
+                    if self.whitelist.contains_key(id, &guard) {
+                        Ok(())
+                    } else {
+                        Err(Denied::NotAllowed)
+                    }
+                }
+                PlayerId::Guest(_) => Err(Denied::Login),
+            },
+        }
+    }
+
+    fn is_host(&self, player: &Player) -> bool {
+        match (&*self.host.read().unwrap(), &player.id) {
+            (Some(host), PlayerId::User(id)) => host.0 == id.as_str(),
+            _ => false,
+        }
+    }
+
+    /// Whether the player is allowed to change elements on the table
+    fn can_edit(&self, player: &Player) -> bool {
+        !self.is_locked() || self.is_host(player)
+    }
+
+    fn name(&self) -> String {
+        self.name.read().unwrap().clone()
+    }
+
+    fn sharing(&self) -> SharingType {
+        self.sharing.read().unwrap().clone()
+    }
+
+    fn host(&self) -> Option<UserId<'static>> {
+        self.host.read().unwrap().clone()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Removes a player from the table. The host can't be kicked.
+    fn kick(&self, player: &PlayerId) -> bool {
+        if let (Some(host), PlayerId::User(id)) = (&*self.host.read().unwrap(), player) {
+            if host.0 == id.as_str() {
+                return false;
+            }
+        }
+        let guard = self.kicked.guard();
+        self.kicked.insert(player.clone(), &guard);
+        true
+    }
+
+    fn set_locked(&self, locked: bool) {
+        self.locked
+            .store(locked, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Makes another user the host. Guests can't host a table.
+    fn transfer_host(&self, player: &PlayerId) -> bool {
+        match player {
+            PlayerId::User(id) => {
+                *self.host.write().unwrap() = Some(UserId(id.clone().into()));
+                true
+            }
+            PlayerId::Guest(_) => false,
+        }
+    }
+
+    fn rename(&self, name: &str) -> bool {
+        let name = name.trim();
+        if name.is_empty() {
+            return false;
+        }
+        *self.name.write().unwrap() = name.to_string();
+        true
+    }
+
+    fn set_sharing(&self, sharing: SharingType) {
+        *self.sharing.write().unwrap() = sharing;
+    }
+
+    fn whitelist_add(&self, users: impl IntoIterator<Item = (String, String)>) {
+        let guard = self.whitelist.guard();
+        for (id, username) in users {
+            self.whitelist.insert(id, username, &guard);
+        }
+    }
+
+    fn whitelist_remove(&self, username: &str) -> bool {
+        let guard = self.whitelist.guard();
+        let before = self.whitelist.len();
+        self.whitelist.retain(|_id, name| name != username, &guard);
+        self.whitelist.len() != before
+    }
+
+    /// Usernames of everyone on the whitelist
+    fn whitelist(&self) -> Vec<String> {
+        let guard = self.whitelist.guard();
+        let mut ret: Vec<_> = self.whitelist.values(&guard).cloned().collect();
+        ret.sort();
+        ret
+    }
+
+    fn next_id(&self) -> u32 {
+        self.cur_id.fetch_add(1, std::sync::atomic::Ordering::AcqRel)
+    }
+
+    /// Whether the item is an icon from a pack loaded on this table
+    fn has_icon(&self, item: &ItemState, icons: &limits::IconIndex) -> bool {
+        match item {
+            ItemState::Icon { icon_pack, icon_id } => {
+                let guard = self.icon_packs.guard();
+                self.icon_packs.contains(icon_pack, &guard)
+                    && icons.contains(*icon_pack, *icon_id)
+            }
+            _ => false,
         }
     }
 
+    fn mark_dirty(&self) {
+        self.dirty.store(true, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Records that someone is using the table, so it shouldn't expire
+    fn touch(&self) {
+        self.last_active
+            .store(Utc::now().timestamp(), std::sync::atomic::Ordering::Release);
+    }
+
+    fn last_active(&self) -> i64 {
+        self.last_active.load(std::sync::atomic::Ordering::Acquire)
+    }
+
     fn tester() -> Self {
         let map = flurry::HashMap::new();
         {
             let guard = map.guard();
-            map.insert(
-                1,
-                ElementState {
-                    icon_pack: 1,
-                    icon_id: 1,
-                    element_id: 1,
-                    top: AtomicUsize::new(0),
-                    left: AtomicUsize::new(0),
-                    public_state: flurry::HashMap::new(),
-                    private_state: flurry::HashMap::new(),
-                    action: flurry::HashMap::new(),
-                },
-                &guard,
-            );
+            map.insert(1, ElementState::new(1, 1, 1, 0, 0), &guard);
         }
         let icon_packs = flurry::HashSet::new();
         {
@@ -237,12 +639,28 @@ impl TableState {
         }
         Self {
             created: chrono::Utc::now(),
-            name: "Default Tester".into(),
-            sharing: SharingType::Public {},
-            host: None,
+            name: RwLock::new("Default Tester".into()),
+            sharing: RwLock::new(SharingType::Public {}),
+            host: RwLock::new(None),
+            locked: AtomicBool::new(false),
+            listed: AtomicBool::new(false),
+            kicked: flurry::HashSet::new(),
+            whitelist: flurry::HashMap::new(),
             cur_id: AtomicU32::new(2),
             elements: map,
             icon_packs,
+            hands: flurry::HashMap::new(),
+            history: Mutex::default(),
+            log: Mutex::default(),
+            log_seq: AtomicU64::new(0),
+            sequencer: Mutex::default(),
+            drags: Mutex::default(),
+            grabs: Mutex::default(),
+            chat: Mutex::default(),
+            cursors: Mutex::default(),
+            present: Mutex::default(),
+            dirty: AtomicBool::new(false),
+            last_active: AtomicI64::new(Utc::now().timestamp()),
         }
     }
 }
@@ -256,13 +674,10 @@ struct ElementState {
     element_id: u32,
     top: AtomicUsize,
     left: AtomicUsize,
-    // TODO: Add state that allows card flipping
-    // We also want an action option, for e.g. a deck
-    //
-    // Private State? e.g. the other side of the card? (What about public state?)
-    // - For decks a list of cards to randomly choose from
-    // - For dice, a list of sides to randomly choose from ( Ideally this could be a special case
-    // of a deck, e.g. it would automatically reset or something?)
+    // Only the public state is ever sent to clients, the private state holds e.g.
+    // - For cards, the front of a face down card, see `card`
+    // - For decks a list of cards to draw from, see `deck`
+    // - For dice, a list of sides to randomly choose from, see `dice`
     public_state: flurry::HashMap<String, Property>,
     #[serde(skip)]
     private_state: flurry::HashMap<String, Property>,
@@ -270,15 +685,17 @@ struct ElementState {
     action: flurry::HashMap<String, Action>,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(crate = "rocket::serde")]
 enum Property {
     Single(ItemState),
     List(flurry::HashSet<ItemState>),
     Obj(flurry::HashMap<String, ItemState>),
+    /// Ordered list, e.g. the cards in a deck
+    Stack(Vec<ItemState>),
 }
 
-#[derive(Debug, Serialize, Hash, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Serialize, Deserialize, Hash, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(crate = "rocket::serde")]
 enum ItemState {
     Icon { icon_pack: u32, icon_id: u32 },
@@ -286,13 +703,99 @@ enum ItemState {
     Str(String),
 }
 
-#[derive(Debug, Serialize, Hash, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Serialize, Deserialize, Hash, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(crate = "rocket::serde")]
 enum Action {
     Draw(String),
     Select(String),
 }
 
+impl Property {
+    /// Every icon in the property, as `(icon_pack, icon_id)`
+    fn icons(&self) -> Vec<(u32, u32)> {
+        match self {
+            Self::Single(item) => item.icon().into_iter().collect(),
+            Self::List(items) => items.iter(&items.guard()).filter_map(ItemState::icon).collect(),
+            Self::Obj(items) => items.values(&items.guard()).filter_map(ItemState::icon).collect(),
+            Self::Stack(items) => items.iter().filter_map(ItemState::icon).collect(),
+        }
+    }
+
+    fn remap_icon_packs(&mut self, ids: &HashMap<u32, u32>) {
+        match self {
+            Self::Single(item) => item.remap_icon_packs(ids),
+            Self::List(items) => {
+                let remapped: flurry::HashSet<_> = {
+                    let guard = items.guard();
+                    items
+                        .iter(&guard)
+                        .map(|item| {
+                            let mut item = item.clone();
+                            item.remap_icon_packs(ids);
+                            item
+                        })
+                        .collect()
+                };
+                *items = remapped;
+            }
+            Self::Obj(items) => {
+                let remapped: flurry::HashMap<_, _> = {
+                    let guard = items.guard();
+                    items
+                        .iter(&guard)
+                        .map(|(k, item)| {
+                            let mut item = item.clone();
+                            item.remap_icon_packs(ids);
+                            (k.clone(), item)
+                        })
+                        .collect()
+                };
+                *items = remapped;
+            }
+            Self::Stack(items) => items.iter_mut().for_each(|i| i.remap_icon_packs(ids)),
+        }
+    }
+}
+
+impl ItemState {
+    /// The icon, as `(icon_pack, icon_id)`, if this is one
+    fn icon(&self) -> Option<(u32, u32)> {
+        match self {
+            Self::Icon { icon_pack, icon_id } => Some((*icon_pack, *icon_id)),
+            _ => None,
+        }
+    }
+
+    fn remap_icon_packs(&mut self, ids: &HashMap<u32, u32>) {
+        if let Self::Icon { icon_pack, .. } = self {
+            *icon_pack = ids.get(icon_pack).copied().unwrap_or(*icon_pack);
+        }
+    }
+}
+
+impl ElementState {
+    fn new(element_id: u32, icon_pack: u32, icon_id: u32, top: usize, left: usize) -> Self {
+        Self {
+            icon_pack,
+            icon_id,
+            element_id,
+            top: AtomicUsize::new(top),
+            left: AtomicUsize::new(left),
+            public_state: flurry::HashMap::new(),
+            private_state: flurry::HashMap::new(),
+            action: flurry::HashMap::new(),
+        }
+    }
+
+    fn public_state(&self) -> HashMap<String, Property> {
+        let guard = self.public_state.guard();
+        self.public_state
+            .iter(&guard)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
 impl Clone for ElementState {
     fn clone(&self) -> Self {
         Self {
@@ -325,42 +828,343 @@ impl std::cmp::PartialEq for ElementState {
 }
 impl std::cmp::Eq for ElementState {}
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct TableConfig {
+    /// Seconds between saving modified tables to the database
+    snapshot_interval: u64,
+    /// Seconds between checking for expired tables
+    cleanup_interval: u64,
+    /// Whether expired tables are kept in the database, or deleted
+    archive_expired: bool,
+    /// Number of changes that can be undone on each table
+    history_limit: usize,
+    /// Number of recent updates kept for clients that reconnect, before they're sent the whole
+    /// table instead
+    resync_buffer: usize,
+    /// Most elements a single table can have
+    element_limit: usize,
+    /// Most icon packs a single table can load
+    pack_limit: usize,
+    /// Most cards a new deck can have
+    deck_size: usize,
+    /// Most faces a new die can have
+    dice_faces: usize,
+    /// Drag previews of each element broadcast per second, see `drag`
+    drag_rate: u32,
+    /// Seconds an element stays held after it was grabbed or last dragged, see `grab`
+    grab_ttl: u64,
+    /// Cursor positions of each player broadcast per second, see `pointer`
+    cursor_rate: u32,
+    /// Chat messages kept with each table
+    chat_limit: usize,
+    /// Most characters in a chat message
+    chat_length: usize,
+    /// Days log entries are kept for, see `replay`. Zero keeps them forever.
+    log_retention: u64,
+    size: limits::TableSize,
+    rate_limit: throttle::RateLimit,
+    idle_ttl: IdleTtl,
+    codes: code::CodeConfig,
+}
+
+impl Default for TableConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_interval: 5,
+            cleanup_interval: 60 * 60,
+            archive_expired: true,
+            history_limit: 100,
+            resync_buffer: 256,
+            element_limit: 500,
+            pack_limit: 20,
+            deck_size: 500,
+            dice_faces: 100,
+            drag_rate: 30,
+            grab_ttl: 10,
+            cursor_rate: 15,
+            chat_limit: 100,
+            chat_length: 500,
+            log_retention: 30,
+            size: limits::TableSize::default(),
+            rate_limit: throttle::RateLimit::default(),
+            idle_ttl: IdleTtl::default(),
+            codes: code::CodeConfig::default(),
+        }
+    }
+}
+
+/// Hours a table can go without any activity before it expires. Zero means the table never
+/// expires.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct IdleTtl {
+    public: u64,
+    password: u64,
+    whitelist: u64,
+}
+
+impl Default for IdleTtl {
+    fn default() -> Self {
+        Self {
+            public: 24,
+            password: 3 * 24,
+            whitelist: 7 * 24,
+        }
+    }
+}
+
+impl IdleTtl {
+    /// Seconds before a table with the given sharing type expires
+    fn for_sharing(&self, sharing: &SharingType) -> Option<i64> {
+        let hours = match sharing {
+            SharingType::Public {} => self.public,
+            SharingType::Password { .. } => self.password,
+            SharingType::Whitelist { .. } => self.whitelist,
+        };
+        if hours == 0 {
+            None
+        } else {
+            Some(hours as i64 * 60 * 60)
+        }
+    }
+}
+
 pub struct GlobalState {
     map: Arc<flurry::HashMap<String, TableState>>,
+    db: MySqlPool,
+    config: TableConfig,
+    icons: limits::IconIndex,
+    limiter: throttle::Limiter,
+    /// When recently deleted tables were deleted, see `my_tables::delete_table`
+    deleted: flurry::HashMap<String, i64>,
+    /// Held while saving tables, so a table can't be deleted while it's being saved
+    saving: Arc<rocket::tokio::sync::Mutex<()>>,
 }
 
 impl GlobalState {
-    pub fn new() -> (Self, impl Future<Output = ()>) {
+    pub async fn new(db: MySqlPool, config: TableConfig) -> Result<Self, sqlx::Error> {
         let map = Arc::new(flurry::HashMap::new());
+        let tables = persist::load_tables(&db).await?;
         {
             let guard = map.guard();
-            map.insert("abc".into(), TableState::tester(), &guard);
+            for (id, table) in tables {
+                map.insert(id, table, &guard);
+            }
+            let _ = map.try_insert("ABC".into(), TableState::tester(), &guard);
         }
-        let cleanup_handle = Arc::clone(&map);
-        (Self { map }, async move {
+        Ok(Self {
+            map,
+            db,
+            config,
+            icons: limits::IconIndex::default(),
+            limiter: throttle::Limiter::default(),
+            deleted: flurry::HashMap::new(),
+            saving: Arc::default(),
+        })
+    }
+
+    /// Background task that periodically saves modified tables, and cleans up old ones.
+    pub fn task(&self) -> impl Future<Output = ()> {
+        let map = Arc::clone(&self.map);
+        let db = self.db.clone();
+        let config = self.config.clone();
+        let saving = Arc::clone(&self.saving);
+        async move {
+            let mut snapshot = rocket::tokio::time::interval(Duration::from_secs(
+                config.snapshot_interval.max(1),
+            ));
+            let mut cleanup = rocket::tokio::time::interval(Duration::from_secs(
+                config.cleanup_interval.max(1),
+            ));
             loop {
-                rocket::tokio::time::sleep(Duration::from_secs(4 * 60 * 60)).await;
-                // TODO: cleanup old tables
-                let guard = cleanup_handle.guard();
-                cleanup_handle.retain(
-                    |_code, _table| {
-                        // true keeps element, so we need to decide when it's okay to remove a
-                        // table
-                        true
-                    },
-                    &guard,
-                );
+                rocket::tokio::select! {
+                    _ = snapshot.tick() => Self::snapshot(&map, &db, &saving).await,
+                    _ = cleanup.tick() => Self::cleanup(&map, &db, &config).await,
+                }
             }
-        })
+        }
+    }
+
+    /// Removes every table that has been idle for longer than its ttl, and either archives or
+    /// deletes it from the database
+    async fn cleanup(
+        map: &flurry::HashMap<String, TableState>,
+        db: &MySqlPool,
+        config: &TableConfig,
+    ) {
+        let now = Utc::now().timestamp();
+        let mut expired = vec![];
+        {
+            let guard = map.guard();
+            map.retain(
+                |id, table| match config.idle_ttl.for_sharing(&table.sharing()) {
+                    Some(ttl) if now - table.last_active() > ttl => {
+                        expired.push((
+                            id.clone(),
+                            table.name(),
+                            persist::SavedTable::from(table).to_json(),
+                        ));
+                        false
+                    }
+                    _ => true,
+                },
+                &guard,
+            );
+        }
+        let mut reclaimed = 0;
+        for (id, name, state) in expired {
+            let res = if config.archive_expired {
+                persist::archive_table(db, &id, &state).await
+            } else {
+                persist::delete_table(db, &id).await
+            };
+            let res = match res {
+                Ok(()) if !config.archive_expired => persist::delete_log(db, &id).await,
+                res => res,
+            };
+            let res = match res {
+                Ok(()) if !config.archive_expired => my_tables::delete_visits(db, &id).await,
+                res => res,
+            };
+            match res {
+                Ok(()) => {
+                    reclaimed += 1;
+                    println!(
+                        "Table `{}` ({}) expired, {}",
+                        id,
+                        name,
+                        if config.archive_expired {
+                            "archived"
+                        } else {
+                            "deleted"
+                        }
+                    );
+                }
+                Err(e) => println!("Failed to remove expired table `{}`: {:?}", id, e),
+            }
+        }
+        if reclaimed > 0 {
+            println!("Reclaimed {} expired tables", reclaimed);
+        }
+        if config.log_retention > 0 {
+            Self::prune_logs(map, db, config).await;
+        }
+    }
+
+    /// Deletes log entries older than `log_retention`
+    async fn prune_logs(
+        map: &flurry::HashMap<String, TableState>,
+        db: &MySqlPool,
+        config: &TableConfig,
+    ) {
+        {
+            // Tables used since the last cleanup get a fresh snapshot, so their old entries can
+            // be pruned once it's old enough
+            let since = Utc::now().timestamp() - config.cleanup_interval as i64;
+            let guard = map.guard();
+            for table in map.values(&guard) {
+                if table.last_active() >= since {
+                    table.log_snapshot(None);
+                }
+            }
+        }
+        let retention = Duration::from_secs(config.log_retention * 24 * 60 * 60);
+        let before = Utc::now().timestamp_millis() - retention.as_millis() as i64;
+        if let Err(e) = persist::prune_log(db, before).await {
+            println!("Failed to prune table logs: {:?}", e);
+        }
+    }
+
+    /// Saves every table that has been modified since the last snapshot
+    async fn snapshot(
+        map: &flurry::HashMap<String, TableState>,
+        db: &MySqlPool,
+        saving: &rocket::tokio::sync::Mutex<()>,
+    ) {
+        let _saving = saving.lock().await;
+        let modified: Vec<_> = {
+            let guard = map.guard();
+            map.iter(&guard)
+                .filter(|(_, t)| t.dirty.swap(false, std::sync::atomic::Ordering::AcqRel))
+                .map(|(id, t)| (id.clone(), persist::SavedTable::from(t).to_json()))
+                .collect()
+        };
+        for (id, state) in modified {
+            if let Err(e) = persist::save_table(db, &id, &state).await {
+                println!("Failed to save table `{}`: {:?}", id, e);
+                let guard = map.guard();
+                if let Some(t) = map.get(&id, &guard) {
+                    t.mark_dirty();
+                }
+            }
+        }
+        let logs: Vec<_> = {
+            let guard = map.guard();
+            map.iter(&guard)
+                .map(|(id, t)| (id.clone(), t.take_log()))
+                .filter(|(_, log)| !log.is_empty())
+                .collect()
+        };
+        for (id, log) in logs {
+            if let Err(e) = persist::save_log(db, &id, &log).await {
+                println!("Failed to save log for table `{}`: {:?}", id, e);
+                let guard = map.guard();
+                if let Some(t) = map.get(&id, &guard) {
+                    t.restore_log(log);
+                }
+            }
+        }
     }
 }
 
+/// The table, as seen by a specific player
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TableView {
+    created: chrono::DateTime<Utc>,
+    name: String,
+    locked: bool,
+    listed: bool,
+    elements: HashMap<u32, ElementState>,
+    icon_packs: Vec<u32>,
+    /// Version of the table, see `sync`
+    version: u64,
+    /// Which load of the table the version belongs to
+    epoch: u32,
+    me: Player,
+    is_host: bool,
+    /// The cards in this player's hand
+    hand: Vec<hand::HandCard>,
+    /// How many cards everyone is holding
+    hands: Vec<hand::HandSize>,
+    /// Elements players are holding
+    grabs: Vec<grab::Held>,
+    /// Everyone connected to the table
+    players: Vec<Player>,
+    /// Recent chat messages, oldest first
+    chat: Vec<chat::Message>,
+}
+
 #[get("/api/table/<id>/state")]
-fn table_state(id: &str, state: &State<GlobalState>) -> APIResponse<TableState> {
+fn table_state(
+    id: &str,
+    state: &State<GlobalState>,
+    player: Player,
+    cookies: &CookieJar<'_>,
+) -> APIResponse<TableView> {
     let state = {
         let guard = state.map.guard();
         match state.map.get(id, &guard) {
-            Some(t) => t.clone(),
+            Some(t) => {
+                if let Err(e) = t.check_access(id, &player, cookies) {
+                    return e.response();
+                }
+                t.touch();
+                t.view(player)
+            }
             None => {
                 return APIResponse::not_found(Error {
                     text: format!("`{}` not found", id),
@@ -371,21 +1175,104 @@ fn table_state(id: &str, state: &State<GlobalState>) -> APIResponse<TableState>
     APIResponse::ok(state)
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-#[serde(crate = "rocket::serde")]
-#[serde(rename_all = "snake_case", untagged)]
-enum SharingType {
-    Public {},
-    Password { password: String },
-    Whitelist {},
+/// Name of the private cookie that remembers the password for a table
+fn password_cookie(id: &str) -> String {
+    format!("table_{}", id)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(crate = "rocket::serde")]
+/// Reasons a user can be denied access to a table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Denied {
+    /// The table is password protected, and the user hasn't entered the password
+    Password,
+    /// The table is invite only, and the user isn't logged in
+    Login,
+    /// The table is invite only, and the user hasn't been invited
+    NotAllowed,
+    /// The host removed the user from the table
+    Kicked,
+    /// Only the host can make this change
+    NotHost,
+}
+
+impl Denied {
+    fn message(&self) -> &'static str {
+        match self {
+            Self::Password => "This table requires a password",
+            Self::Login => "You must be logged in to join this table",
+            Self::NotAllowed => "You haven't been invited to this table",
+            Self::Kicked => "You have been removed from this table",
+            Self::NotHost => "Only the host can do that",
+        }
+    }
+
+    fn status(&self) -> Status {
+        match self {
+            Self::Password | Self::Login => Status::Unauthorized,
+            _ => Status::Forbidden,
+        }
+    }
+
+    fn response<T>(&self) -> APIResponse<T> {
+        let error = Error {
+            text: self.message().to_string(),
+        };
+        match self {
+            Self::Password | Self::Login => APIResponse::unauthorized(error),
+            _ => APIResponse::forbidden(error),
+        }
+    }
+}
+
+// Since this is untagged, variants are tried in order. `Public` accepts any object, so it has to
+// come after any variant with fields.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "snake_case", untagged)]
+enum SharingType {
+    Password {
+        password: String,
+    },
+    Whitelist {
+        /// Usernames to add to the whitelist. This is only used when creating the table or
+        /// changing its sharing, the whitelist itself is stored on the table.
+        invite: Vec<String>,
+    },
+    Public {},
+}
+
+impl SharingType {
+    /// Removes the usernames to invite, leaving an empty list
+    fn take_invites(&mut self) -> Vec<String> {
+        match self {
+            Self::Whitelist { invite } => std::mem::take(invite),
+            _ => vec![],
+        }
+    }
+
+    /// Copy that is safe to send to clients
+    fn hidden(&self) -> Self {
+        match self {
+            Self::Password { .. } => Self::Password {
+                password: String::new(),
+            },
+            s => s.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
 struct TableOptions<'a> {
     name: &'a str,
     sharing: SharingType,
     icons: Vec<&'a str>,
+    /// Template to copy elements and icon packs from
+    #[serde(default)]
+    template: Option<u32>,
+    /// Show the table in the lobby, if it's public
+    #[serde(default)]
+    listed: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -394,15 +1281,37 @@ struct TableName {
     id: String,
 }
 
+/// Number of codes to try before giving up on creating a table
+const MAX_CODE_ATTEMPTS: usize = 16;
+
 #[post("/api/table/create", data = "<options>")]
 async fn create_table(
     options: Json<TableOptions<'_>>,
     state: &State<GlobalState>,
     user: PUser<'_>,
+    player: Player,
     mut db: DBConnInst,
 ) -> APIResponse<TableName> {
-    let options = options.into_inner();
+    let mut options = options.into_inner();
     let name = options.name.to_string();
+    if matches!(options.sharing, SharingType::Whitelist { .. }) && user.is_none() {
+        return APIResponse::unauthorized(Error {
+            text: format!("Guests can't create invite only tables"),
+        });
+    }
+    let whitelist = match lookup_users(&options.sharing.take_invites(), &mut db).await {
+        Ok(users) => users,
+        Err(sqlx::Error::RowNotFound) => {
+            return APIResponse::not_found(Error {
+                text: format!("User not found"),
+            })
+        }
+        Err(_e) => {
+            return APIResponse::internal_error(Error {
+                text: format!("Internal Error"),
+            });
+        }
+    };
     let ids = match lookup_ids(options.icons, &mut db).await {
         Ok(ids) => ids,
         Err(sqlx::Error::RowNotFound) => {
@@ -416,23 +1325,99 @@ async fn create_table(
             });
         }
     };
-    let table = TableState::new(
-        name,
-        options.sharing,
-        user.as_ref().map(|u| u.id().to_owned()),
-        ids,
-    );
-    let guard = state.map.guard();
-    let mut rng = rand::thread_rng();
-    let id = format!("{:X}", rng.gen::<u16>());
-    match state.map.try_insert(id.clone(), table, &guard) {
-        Ok(_) => return APIResponse::ok(TableName { id }),
-        Err(_) => {
+    let table = match options.template {
+        Some(template) => {
+            let table = match template::load(template, &player, &mut db).await {
+                Ok(table) => table,
+                Err(e) => return e,
+            };
+            table.rename(&name);
+            table.set_sharing(options.sharing);
+            {
+                let guard = table.icon_packs.guard();
+                let ids_guard = ids.guard();
+                for id in ids.iter(&ids_guard) {
+                    table.icon_packs.insert(*id, &guard);
+                }
+            }
+            table
+        }
+        None => TableState::new(
+            name,
+            options.sharing,
+            user.as_ref().map(|u| u.id().to_owned()),
+            ids,
+        ),
+    };
+    table.whitelist_add(whitelist);
+    table.set_listed(options.listed);
+    table.log_snapshot(Some(&player));
+    add_table(state, table, &mut db).await
+}
+
+/// Gives a new table an unused code, and saves it
+async fn add_table(
+    state: &GlobalState,
+    table: TableState,
+    db: &mut DBConnInst,
+) -> APIResponse<TableName> {
+    let saved = persist::SavedTable::from(&table).to_json();
+    let mut table = Some(table);
+    let mut id = None;
+    for _ in 0..MAX_CODE_ATTEMPTS {
+        let code = state.config.codes.generate();
+        // Archived tables keep their code, so it can't be reused
+        match persist::table_exists(db.con(), &code).await {
+            Ok(false) => (),
+            Ok(true) => continue,
+            Err(_e) => {
+                return APIResponse::internal_error(Error {
+                    text: format!("Internal Error"),
+                });
+            }
+        }
+        let guard = state.map.guard();
+        match state.map.try_insert(code.clone(), table.take().unwrap(), &guard) {
+            Ok(_) => {
+                id = Some(code);
+                break;
+            }
+            Err(e) => table = Some(e.not_inserted),
+        }
+    }
+    let id = match id {
+        Some(id) => id,
+        None => {
+            println!("Failed to find an unused table code, consider making codes longer");
             return APIResponse::internal_error(Error {
                 text: format!("Internal Error"),
             });
         }
+    };
+    if let Err(e) = persist::save_table(db.con(), &id, &saved).await {
+        // The table still exists in memory, so the next snapshot will try again
+        println!("Failed to save table `{}`: {:?}", id, e);
+        let guard = state.map.guard();
+        if let Some(t) = state.map.get(&id, &guard) {
+            t.mark_dirty();
+        }
     }
+    APIResponse::ok(TableName { id })
+}
+
+/// Finds the `UserId` for each username, returning pairs of ids and usernames
+async fn lookup_users(
+    names: &[String],
+    db: &mut DBConnInst,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let mut ret = Vec::with_capacity(names.len());
+    for name in names {
+        let row = sqlx::query!("SELECT id, username FROM users WHERE username = ?", name)
+            .fetch_one(db.con())
+            .await?;
+        ret.push((row.id, row.username));
+    }
+    Ok(ret)
 }
 
 async fn lookup_ids(
@@ -490,22 +1475,22 @@ pub enum IconType {
 
 #[get("/api/icons/<id>")]
 async fn get_icon_pack(id: u32, mut db: DBConnInst) -> APIResponse<IconPack> {
-    let name = match sqlx::query!("SELECT name FROM icon_packs WHERE id = ?", id)
+    match load_icon_pack(id, &mut db).await {
+        Ok(pack) => APIResponse::ok(pack),
+        Err(sqlx::Error::RowNotFound) => APIResponse::not_found(Error {
+            text: format!("Icon pack not found"),
+        }),
+        Err(_e) => APIResponse::internal_error(Error {
+            text: format!("Internal Error"),
+        }),
+    }
+}
+
+async fn load_icon_pack(id: u32, db: &mut DBConnInst) -> Result<IconPack, sqlx::Error> {
+    let name = sqlx::query!("SELECT name FROM icon_packs WHERE id = ?", id)
         .fetch_one(db.con())
-        .await
-    {
-        Ok(row) => row.name,
-        Err(sqlx::Error::RowNotFound) => {
-            return APIResponse::not_found(Error {
-                text: format!("Icon pack not found"),
-            })
-        }
-        Err(_e) => {
-            return APIResponse::internal_error(Error {
-                text: format!("Icon pack not found"),
-            })
-        }
-    };
+        .await?
+        .name;
     let mut icons = vec![];
     let mut stream = sqlx::query!(
         "SELECT ty, icon_id, name, img FROM icons WHERE table_id = ?",
@@ -513,38 +1498,35 @@ async fn get_icon_pack(id: u32, mut db: DBConnInst) -> APIResponse<IconPack> {
     )
     .fetch(db.con());
     use rocket::futures::StreamExt;
-    loop {
-        match stream.next().await {
-            None => break,
-            Some(Ok(row)) => icons.push(match IconType::try_from(row.ty) {
-                Ok(IconType::Image) => Icon::Image {
-                    id: row.icon_id,
-                    name: row.name,
-                    src: row.img,
-                },
-                Ok(IconType::Icon) => Icon::Icon {
-                    id: row.icon_id,
-                    name: row.name,
-                    class: row.img,
-                },
-                Ok(IconType::Svg) => Icon::Svg {
-                    id: row.icon_id,
-                    name: row.name,
-                    src: row.img,
-                },
-                Err(_e) => todo!(),
-            }),
-            Some(Err(_e)) => {
-                return APIResponse::internal_error(Error {
-                    text: format!("Internal Error"),
-                })
+    while let Some(row) = stream.next().await {
+        let row = row?;
+        icons.push(match IconType::try_from(row.ty) {
+            Ok(IconType::Image) => Icon::Image {
+                id: row.icon_id,
+                name: row.name,
+                src: row.img,
+            },
+            Ok(IconType::Icon) => Icon::Icon {
+                id: row.icon_id,
+                name: row.name,
+                class: row.img,
+            },
+            Ok(IconType::Svg) => Icon::Svg {
+                id: row.icon_id,
+                name: row.name,
+                src: row.img,
+            },
+            Err(()) => {
+                return Err(sqlx::Error::Decode(
+                    format!("Icon {} has unknown type {}", row.icon_id, row.ty).into(),
+                ))
             }
-        }
+        });
     }
-    APIResponse::ok(IconPack { name, icons })
+    Ok(IconPack { name, icons })
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 #[serde(tag = "t", rename_all = "snake_case")]
 enum TableUpdate {
@@ -556,6 +1538,64 @@ enum TableUpdate {
         top: usize,
         left: usize,
     },
+    /// Sent while an element is being dragged, so everyone can see it move. The element doesn't
+    /// actually move until the drag ends, with a `Position` update.
+    Drag {
+        id: u32,
+        top: usize,
+        left: usize,
+    },
+    /// Hold an element, so nobody else can move it
+    Grab {
+        id: u32,
+    },
+    /// Let go of an element
+    Release {
+        id: u32,
+    },
+    /// Sent by the server when someone grabs an element
+    #[serde(skip_deserializing)]
+    Grabbed {
+        #[serde(flatten)]
+        held: grab::Held,
+    },
+    /// Where the player's cursor is, sent while it moves over the table
+    Cursor {
+        top: usize,
+        left: usize,
+    },
+    /// Sent by the server when someone's cursor moves
+    #[serde(skip_deserializing)]
+    CursorMoved {
+        player: Player,
+        top: usize,
+        left: usize,
+    },
+    /// Flash a spot on the table, to draw everyone's attention to it
+    Ping {
+        top: usize,
+        left: usize,
+    },
+    /// Sent by the server when someone pings the table
+    #[serde(skip_deserializing)]
+    Pinged {
+        player: Player,
+        top: usize,
+        left: usize,
+    },
+    /// Send a chat message to everyone at the table
+    Chat {
+        text: String,
+    },
+    /// Sent by the server when someone sends a chat message
+    #[serde(skip_deserializing)]
+    Message {
+        #[serde(flatten)]
+        message: chat::Message,
+    },
+    DeleteMessage {
+        id: u32,
+    },
     IconpackLoad {
         pack: u32,
     },
@@ -566,9 +1606,232 @@ enum TableUpdate {
         top: usize,
         left: usize,
     },
+    /// Create a deck of cards. The cards are never sent to clients.
+    DeckCreate {
+        icon_pack: u32,
+        icon_id: u32,
+        id: u32,
+        top: usize,
+        left: usize,
+        #[serde(default)]
+        cards: Vec<ItemState>,
+    },
+    /// Create a card. The face is only sent to clients if the card is face up.
+    CardCreate {
+        icon_pack: u32,
+        icon_id: u32,
+        id: u32,
+        top: usize,
+        left: usize,
+        #[serde(default)]
+        face: Option<ItemState>,
+        #[serde(default)]
+        face_up: bool,
+    },
+    /// Turn a card over
+    Flip {
+        id: u32,
+    },
+    /// Create a die, or anything else that lands on a random face
+    DiceCreate {
+        icon_pack: u32,
+        icon_id: u32,
+        id: u32,
+        top: usize,
+        left: usize,
+        faces: Vec<ItemState>,
+    },
     Action {
+        id: u32,
         act: String,
     },
+    /// Roll several dice at once
+    Roll {
+        ids: Vec<u32>,
+    },
+    /// Pick an element up into your hand
+    ToHand {
+        id: u32,
+    },
+    /// Play a card from your hand onto the table
+    FromHand {
+        index: usize,
+        top: usize,
+        left: usize,
+        #[serde(default)]
+        face_up: bool,
+    },
+    /// Sent by the server when an element's public state changes
+    PublicState {
+        id: u32,
+        public_state: HashMap<String, Property>,
+    },
+    /// Reverse your last change, or anyone's if you're the host
+    Undo {},
+    /// Reapply the last change you undid
+    Redo {},
+    /// Sent by a client when it reconnects, with the last version it saw and its epoch
+    Resync {
+        version: u64,
+        epoch: u32,
+    },
+    /// Sent by the server when a client has missed too many updates to catch up
+    #[serde(skip_deserializing)]
+    Snapshot {
+        state: Box<TableView>,
+    },
+    /// Sent by the server to the owner of a hand, when it changes
+    Hand {
+        cards: Vec<hand::HandCard>,
+    },
+    /// Sent by the server when the number of cards in someone's hand changes
+    HandSize {
+        player: Player,
+        count: usize,
+    },
+    /// Sent by the server after dice are rolled
+    Rolled {
+        player: Player,
+        rolls: Vec<dice::DiceRoll>,
+    },
+    /// Sent by the server when the host deletes the table
+    #[serde(skip_deserializing)]
+    Deleted {},
+    /// Sent by the server when a player connects to the table
+    #[serde(skip_deserializing)]
+    Joined {
+        player: Player,
+    },
+    /// Sent by the server when a player's last connection to the table closes
+    #[serde(skip_deserializing)]
+    Left {
+        player: Player,
+    },
+    /// Sent by the server, only to the player whose update was rejected
+    #[serde(skip_deserializing)]
+    Rejected {
+        /// The name of the rejected update
+        update: String,
+        reason: reject::Reason,
+        message: String,
+        /// The element the update tried to change
+        id: Option<u32>,
+        /// The element as the server sees it, or none if it doesn't exist
+        element: Option<ElementState>,
+    },
+    Kick {
+        player: PlayerId,
+    },
+    Lock {
+        locked: bool,
+    },
+    /// Show or hide the table in the lobby
+    Listed {
+        listed: bool,
+    },
+    TransferHost {
+        player: PlayerId,
+    },
+    Rename {
+        name: String,
+    },
+    Sharing {
+        sharing: SharingType,
+    },
+}
+
+impl TableUpdate {
+    /// Whether this update changes the elements on the table
+    fn is_edit(&self) -> bool {
+        matches!(
+            self,
+            Self::ElementDelete { .. }
+                | Self::Position { .. }
+                | Self::Drag { .. }
+                | Self::Grab { .. }
+                | Self::Release { .. }
+                | Self::IconpackLoad { .. }
+                | Self::ElementCreate { .. }
+                | Self::DeckCreate { .. }
+                | Self::CardCreate { .. }
+                | Self::Flip { .. }
+                | Self::DiceCreate { .. }
+                | Self::Action { .. }
+                | Self::Roll { .. }
+                | Self::ToHand { .. }
+                | Self::FromHand { .. }
+                | Self::Undo {}
+                | Self::Redo {}
+        )
+    }
+
+    /// Whether this update can only be sent by the server
+    fn server_only(&self) -> bool {
+        matches!(
+            self,
+            Self::PublicState { .. }
+                | Self::Snapshot { .. }
+                | Self::Hand { .. }
+                | Self::HandSize { .. }
+                | Self::Rolled { .. }
+                | Self::Deleted {}
+                | Self::Joined { .. }
+                | Self::Left { .. }
+                | Self::Rejected { .. }
+                | Self::Grabbed { .. }
+                | Self::CursorMoved { .. }
+                | Self::Pinged { .. }
+                | Self::Message { .. }
+        )
+    }
+
+    /// Whether the server sends the results of this update, instead of the update itself
+    fn replaced(&self) -> bool {
+        matches!(
+            self,
+            Self::Action { .. }
+                | Self::Grab { .. }
+                | Self::Cursor { .. }
+                | Self::Ping { .. }
+                | Self::Chat { .. }
+                | Self::Roll { .. }
+                | Self::Flip { .. }
+                | Self::ToHand { .. }
+                | Self::FromHand { .. }
+                | Self::Undo {}
+                | Self::Redo {}
+                | Self::Resync { .. }
+        )
+    }
+
+    /// Whether this update changes the table, and should be logged
+    fn changes_table(&self) -> bool {
+        !matches!(
+            self,
+            Self::Resync { .. }
+                | Self::Drag { .. }
+                | Self::Grab { .. }
+                | Self::Release { .. }
+                | Self::Cursor { .. }
+                | Self::Ping { .. }
+                | Self::Chat { .. }
+                | Self::DeleteMessage { .. }
+        )
+    }
+
+    /// Whether only the host can make this update
+    fn host_only(&self) -> bool {
+        matches!(
+            self,
+            Self::Kick { .. }
+                | Self::Lock { .. }
+                | Self::Listed { .. }
+                | Self::DeleteMessage { .. }
+                | Self::TransferHost { .. }
+                | Self::Rename { .. }
+                | Self::Sharing { .. }
+        )
+    }
 }
 #[message("/ws/table/<id>", data = "<update>")]//, allowed_origins = ["pomesdev.com", "localhost"]
 async fn handle_message(
@@ -576,74 +1839,583 @@ async fn handle_message(
     state: &State<GlobalState>,
     mut update: Json<TableUpdate>,
     ws: &Channel<'_>,
-    user: PUser<'_>,
+    player: Player,
+    cookies: &CookieJar<'_>,
+    addr: SocketAddr,
 ) {
+    let verdict = state.limiter.check(addr, &player.id, &update, &state.config.rate_limit);
+    if verdict == throttle::Verdict::Ignore {
+        return;
+    }
+    let allowed = {
+        let guard = state.map.guard();
+        state
+            .map
+            .get(id, &guard)
+            .map(|t| t.check_access(id, &player, cookies).is_ok())
+    };
+    let allowed = match allowed {
+        Some(allowed) => allowed,
+        None => {
+            // Tell everyone still connected, which they'll see on their next message
+            if my_tables::was_deleted(state, id) {
+                let update: sync::Sequenced = TableUpdate::Deleted {}.into();
+                ws.broadcast(Json(update)).await;
+                ws.close().await;
+            }
+            return;
+        }
+    };
+    let config = &state.config;
+    if allowed
+        && verdict == throttle::Verdict::Allow
+        && !update.too_many_items(config.deck_size, config.dice_faces)
     {
+        // Loaded before taking the guard again, since it may need the database
+        state.icons.load(&update.icon_packs(), &state.db).await;
+    }
+    let (broadcasts, private, close, flush) = {
         let guard = state.map.guard();
-        if let Some(t) = state.map.get(id, &guard) {
-            match &mut *update {
-                TableUpdate::Position { id, top, left } => {
-                    let el_guard = t.elements.guard();
-                    if let Some(el) = t.elements.get(id, &el_guard) {
-                        el.top.store(*top, std::sync::atomic::Ordering::Release);
-                        el.left.store(*left, std::sync::atomic::Ordering::Release);
-                    } else {
-                        return;
-                    }
-                }
-                TableUpdate::IconpackLoad { pack } => {
-                    let packs_guard = t.icon_packs.guard();
-                    if !t.icon_packs.insert(*pack, &packs_guard) {
-                        // Icon pack was already loaded
-                        return;
-                    }
+        let t = match state.map.get(id, &guard) {
+            Some(t) => t,
+            None => return,
+        };
+        // Changes made through the REST api go out first
+        let mut broadcasts = t.take_queued();
+        let mut flush = None;
+        let (private, close) = if t.check_access(id, &player, cookies).is_err() {
+            // e.g. the player was kicked, so they shouldn't see anything else
+            (vec![TableState::denied(&update).into()], true)
+        } else {
+            let outcome = match verdict.reason() {
+                Some(reason) => Err(reason),
+                None => {
+                    t.touch();
+                    apply_update(t, &mut update, &player, &state.config, &state.icons)
                 }
-                TableUpdate::ElementCreate {
-                    icon_pack,
-                    icon_id,
-                    id,
-                    top,
-                    left,
-                } => {
-                    {
-                        let icon_guard = t.icon_packs.guard();
-                        if !t.icon_packs.contains(icon_pack, &icon_guard) {
-                            // Icon pack not loaded
-                            return;
-                        }
-                    }
-                    *id = t.cur_id.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
-                    let el_guard = t.elements.guard();
-                    t.elements.insert(
-                        *id,
-                        ElementState {
-                            icon_pack: *icon_pack,
-                            icon_id: *icon_id,
-                            element_id: *id,
-                            top: AtomicUsize::new(*top),
-                            left: AtomicUsize::new(*left),
-                            public_state: flurry::HashMap::new(),
-                            private_state: flurry::HashMap::new(),
-                            action: flurry::HashMap::new(),
-                        },
-                        &el_guard,
-                    );
+            };
+            match outcome {
+                Ok((sent, private, later)) => {
+                    broadcasts.extend(sent);
+                    flush = later;
+                    (private, false)
                 }
-                TableUpdate::ElementDelete { id } => {
-                    let el_guard = t.elements.guard();
-                    if t.elements.remove(id, &el_guard).is_none() {
-                        // Element did not exist
-                        return;
-                    }
-                } //_ => todo!(),
-                TableUpdate::Action { act } => {
-                    println!("TODO: action {}", act);
-                    return;
+                Err(reason) => (
+                    vec![t.rejection(&update, reason).into()],
+                    verdict == throttle::Verdict::Disconnect,
+                ),
+            }
+        };
+        // The connection is gone as soon as it's closed, rather than when the leave arrives
+        if close {
+            broadcasts.extend(t.sequence(t.leave(addr), state.config.resync_buffer));
+        }
+        (broadcasts, private, close, flush)
+    };
+    // Hidden information, like the cards in a hand, and rejections are only sent on this
+    // connection. They go first, so a new hand arrives before the broadcast of the hand's size.
+    for update in private {
+        ws.send(Json(update)).await;
+    }
+    for update in broadcasts {
+        ws.broadcast(Json(update)).await;
+    }
+    if close {
+        state.limiter.forget(addr);
+        ws.close().await;
+    } else if let Some((preview, after)) = flush {
+        // Whatever is latest once the tick is over still has to be sent
+        rocket::tokio::time::sleep(after).await;
+        let update = {
+            let guard = state.map.guard();
+            state
+                .map
+                .get(id, &guard)
+                .and_then(|t| t.flush_preview(&preview, state.config.resync_buffer))
+        };
+        if let Some(update) = update {
+            ws.broadcast(Json(update)).await;
+        }
+    }
+}
+
+#[join("/ws/table/<id>")]
+async fn ws_join(
+    id: &str,
+    state: &State<GlobalState>,
+    ws: &Channel<'_>,
+    player: Player,
+    cookies: &CookieJar<'_>,
+    addr: SocketAddr,
+) {
+    let connected = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) if t.check_access(id, &player, cookies).is_ok() => {
+                let mut broadcasts = t.take_queued();
+                if t.connect(addr, &player) {
+                    let joined = TableUpdate::Joined { player };
+                    broadcasts.extend(t.sequence(vec![joined], state.config.resync_buffer));
                 }
+                Some(broadcasts)
+            }
+            Some(_) => return,
+            None => None,
+        }
+    };
+    let broadcasts = match connected {
+        Some(broadcasts) => broadcasts,
+        None => {
+            if my_tables::was_deleted(state, id) {
+                let update: sync::Sequenced = TableUpdate::Deleted {}.into();
+                ws.send(Json(update)).await;
+                ws.close().await;
             }
-        } else {
             return;
         }
+    };
+    for update in broadcasts {
+        ws.broadcast(Json(update)).await;
     }
-    ws.broadcast(update).await;
+}
+
+#[leave("/ws/table/<id>")]
+async fn ws_leave(id: &str, state: &State<GlobalState>, ws: &Channel<'_>, addr: SocketAddr) {
+    state.limiter.forget(addr);
+    let broadcasts = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) => {
+                let mut broadcasts = t.take_queued();
+                broadcasts.extend(t.sequence(t.leave(addr), state.config.resync_buffer));
+                broadcasts
+            }
+            None => return,
+        }
+    };
+    for update in broadcasts {
+        ws.broadcast(Json(update)).await;
+    }
+}
+
+/// Updates to broadcast to everyone, updates to send back to only the player, and something to
+/// broadcast once its tick is over, see `coalesce`
+type Applied = (
+    Vec<sync::Sequenced>,
+    Vec<sync::Sequenced>,
+    Option<(coalesce::Preview, Duration)>,
+);
+
+/// Applies an update sent by a player
+fn apply_update(
+    t: &TableState,
+    update: &mut TableUpdate,
+    player: &Player,
+    config: &TableConfig,
+    icons: &limits::IconIndex,
+) -> Result<Applied, Reason> {
+    if update.server_only() {
+        return Err(Reason::ServerOnly);
+    }
+    if update.is_edit() && !t.can_edit(player) {
+        return Err(Reason::Locked);
+    }
+    if update.host_only() && !t.is_host(player) {
+        return Err(Reason::NotHost);
+    }
+    if update.adds_element() && t.is_full(config.element_limit) {
+        return Err(Reason::TooManyElements);
+    }
+    if update.too_many_items(config.deck_size, config.dice_faces) {
+        return Err(Reason::TooManyItems);
+    }
+    // Nobody else can change an element while it's held
+    if let Some(id) = update.element_id() {
+        t.check_grab(id, player)?;
+    }
+    // Updates to send instead of the original one
+    let mut results = vec![];
+    // Updates only sent back to this player
+    let mut private = vec![];
+    let mut resync = vec![];
+    // How to reverse this update
+    let mut op = None;
+    // Set when the update itself is broadcast later, see `coalesce`
+    let mut deferred = None;
+    let limit = config.history_limit;
+    match &mut *update {
+        TableUpdate::Position { id, top, left } => {
+            config.size.clamp(top, left);
+            // Moving an element lets go of it
+            t.release(*id);
+            let el_guard = t.elements.guard();
+            if let Some(el) = t.elements.get(id, &el_guard) {
+                let from = (
+                    el.top.swap(*top, std::sync::atomic::Ordering::AcqRel),
+                    el.left.swap(*left, std::sync::atomic::Ordering::AcqRel),
+                );
+                op = Some(history::Op::Move {
+                    id: *id,
+                    from,
+                    to: (*top, *left),
+                });
+            } else {
+                return Err(Reason::UnknownElement);
+            }
+            // The move is made now, but it may be broadcast once the tick is over
+            deferred = match t.drag_tick(*id, (*update).clone(), config.drag_rate) {
+                coalesce::Offer::Now => None,
+                coalesce::Offer::FlushAfter(after) => Some(Some(after)),
+                coalesce::Offer::Pending => Some(None),
+            };
+        }
+        TableUpdate::Drag { id, top, left } => {
+            config.size.clamp(top, left);
+            {
+                let el_guard = t.elements.guard();
+                if !t.elements.contains_key(id, &el_guard) {
+                    return Err(Reason::UnknownElement);
+                }
+            }
+            // Dragging keeps the element held
+            t.grab(*id, player, config.grab_ttl)?;
+            // Previews skip the history, log and versioning, and most are replaced by later ones
+            let preview = coalesce::Preview::Element(*id);
+            return Ok(match t.drag_tick(*id, (*update).clone(), config.drag_rate) {
+                coalesce::Offer::Now => (vec![(*update).clone().into()], vec![], None),
+                coalesce::Offer::FlushAfter(after) => (vec![], vec![], Some((preview, after))),
+                coalesce::Offer::Pending => (vec![], vec![], None),
+            });
+        }
+        // Like drag previews, grabs aren't saved, logged or versioned
+        TableUpdate::Grab { id } => {
+            {
+                let el_guard = t.elements.guard();
+                if !t.elements.contains_key(id, &el_guard) {
+                    return Err(Reason::UnknownElement);
+                }
+            }
+            let held = t.grab(*id, player, config.grab_ttl)?;
+            return Ok((vec![TableUpdate::Grabbed { held }.into()], vec![], None));
+        }
+        TableUpdate::Release { id } => {
+            if !t.release(*id) {
+                return Ok((vec![], vec![], None));
+            }
+            return Ok((vec![(*update).clone().into()], vec![], None));
+        }
+        // Cursors and pings are only shown, they don't change the table
+        TableUpdate::Cursor { top, left } => {
+            config.size.clamp(top, left);
+            let (top, left) = (*top, *left);
+            let moved = TableUpdate::CursorMoved {
+                player: player.clone(),
+                top,
+                left,
+            };
+            return Ok(match t.cursor_tick(&player.id, moved.clone(), config.cursor_rate) {
+                coalesce::Offer::Now => (vec![moved.into()], vec![], None),
+                coalesce::Offer::FlushAfter(after) => {
+                    let preview = coalesce::Preview::Cursor(player.id.clone());
+                    (vec![], vec![], Some((preview, after)))
+                }
+                coalesce::Offer::Pending => (vec![], vec![], None),
+            });
+        }
+        TableUpdate::Ping { top, left } => {
+            config.size.clamp(top, left);
+            let (top, left) = (*top, *left);
+            let player = player.clone();
+            let pinged = TableUpdate::Pinged { player, top, left };
+            return Ok((vec![pinged.into()], vec![], None));
+        }
+        TableUpdate::IconpackLoad { pack } => {
+            if !icons.has_pack(*pack) {
+                return Err(Reason::UnknownPack);
+            }
+            let packs_guard = t.icon_packs.guard();
+            if t.icon_packs.contains(pack, &packs_guard) {
+                return Err(Reason::PackAlreadyLoaded);
+            }
+            if t.icon_packs.len() >= config.pack_limit {
+                return Err(Reason::TooManyPacks);
+            }
+            t.icon_packs.insert(*pack, &packs_guard);
+        }
+        TableUpdate::ElementCreate {
+            icon_pack,
+            icon_id,
+            id,
+            top,
+            left,
+        } => {
+            let icon = ItemState::Icon {
+                icon_pack: *icon_pack,
+                icon_id: *icon_id,
+            };
+            if !t.has_icon(&icon, icons) {
+                return Err(Reason::UnknownIcon);
+            }
+            config.size.clamp(top, left);
+            *id = t.next_id();
+            let el = ElementState::new(*id, *icon_pack, *icon_id, *top, *left);
+            op = Some(history::Op::Create(el.clone()));
+            let el_guard = t.elements.guard();
+            t.elements.insert(*id, el, &el_guard);
+        }
+        TableUpdate::DeckCreate {
+            icon_pack,
+            icon_id,
+            id,
+            top,
+            left,
+            cards,
+        } => {
+            let back = ItemState::Icon {
+                icon_pack: *icon_pack,
+                icon_id: *icon_id,
+            };
+            if !t.has_icon(&back, icons) || !cards.iter().all(|c| t.has_icon(c, icons)) {
+                return Err(Reason::UnknownIcon);
+            }
+            config.size.clamp(top, left);
+            *id = t.next_id();
+            let deck = ElementState::new_deck(
+                *id,
+                *icon_pack,
+                *icon_id,
+                *top,
+                *left,
+                std::mem::take(cards),
+            );
+            results.push(TableUpdate::PublicState {
+                id: *id,
+                public_state: deck.public_state(),
+            });
+            op = Some(history::Op::Create(deck.clone()));
+            let el_guard = t.elements.guard();
+            t.elements.insert(*id, deck, &el_guard);
+        }
+        TableUpdate::CardCreate {
+            icon_pack,
+            icon_id,
+            id,
+            top,
+            left,
+            face,
+            face_up,
+        } => {
+            let back = ItemState::Icon {
+                icon_pack: *icon_pack,
+                icon_id: *icon_id,
+            };
+            // Taking the face means it isn't broadcast with the update
+            let face = match face.take() {
+                Some(face) if t.has_icon(&back, icons) && t.has_icon(&face, icons) => face,
+                Some(_) => return Err(Reason::UnknownIcon),
+                None => return Err(Reason::Invalid),
+            };
+            config.size.clamp(top, left);
+            *id = t.next_id();
+            let card = ElementState::new_card(
+                *id, *icon_pack, *icon_id, *top, *left, face, *face_up,
+            );
+            results.push(TableUpdate::PublicState {
+                id: *id,
+                public_state: card.public_state(),
+            });
+            op = Some(history::Op::Create(card.clone()));
+            let el_guard = t.elements.guard();
+            t.elements.insert(*id, card, &el_guard);
+        }
+        TableUpdate::Flip { id } => match t.flip(*id) {
+            Some(updates) => results = updates,
+            None => return Err(Reason::Invalid),
+        },
+        TableUpdate::DiceCreate {
+            icon_pack,
+            icon_id,
+            id,
+            top,
+            left,
+            faces,
+        } => {
+            let icon = ItemState::Icon {
+                icon_pack: *icon_pack,
+                icon_id: *icon_id,
+            };
+            if faces.is_empty() {
+                return Err(Reason::Invalid);
+            }
+            if !t.has_icon(&icon, icons) {
+                return Err(Reason::UnknownIcon);
+            }
+            // Faces can also be numbers or text, which don't need an icon pack
+            if !faces
+                .iter()
+                .all(|f| !matches!(f, ItemState::Icon { .. }) || t.has_icon(f, icons))
+            {
+                return Err(Reason::UnknownIcon);
+            }
+            config.size.clamp(top, left);
+            *id = t.next_id();
+            let dice = ElementState::new_dice(
+                *id,
+                *icon_pack,
+                *icon_id,
+                *top,
+                *left,
+                std::mem::take(faces),
+            );
+            results.push(TableUpdate::PublicState {
+                id: *id,
+                public_state: dice.public_state(),
+            });
+            op = Some(history::Op::Create(dice.clone()));
+            let el_guard = t.elements.guard();
+            t.elements.insert(*id, dice, &el_guard);
+        }
+        TableUpdate::ElementDelete { id } => {
+            let el_guard = t.elements.guard();
+            t.end_drag(*id);
+            t.release(*id);
+            match t.elements.remove(id, &el_guard) {
+                Some(el) => op = Some(history::Op::Delete(el.clone())),
+                None => return Err(Reason::UnknownElement),
+            }
+        } //_ => todo!(),
+        TableUpdate::Action { id, act } if act == hand::DRAW_HAND => {
+            match t.draw_to_hand(*id, player) {
+                Some(updates) => {
+                    results = updates.public;
+                    private = updates.private;
+                }
+                None => return Err(Reason::Invalid),
+            }
+        }
+        TableUpdate::Action { id, act } if act == dice::ROLL => match t.roll(&[*id], player) {
+            Some(updates) => results = updates,
+            None => return Err(Reason::Invalid),
+        },
+        TableUpdate::Action { id, act } => match t.deck_action(*id, act, &player.id) {
+            Some(updates) => {
+                results = updates.public;
+                private = updates.private;
+            }
+            None => return Err(Reason::Invalid),
+        },
+        TableUpdate::Roll { ids } => {
+            for id in ids.iter() {
+                t.check_grab(*id, player)?;
+            }
+            match t.roll(ids, player) {
+                Some(updates) => results = updates,
+                None => return Err(Reason::Invalid),
+            }
+        }
+        TableUpdate::ToHand { id } => match t.take_into_hand(*id, player) {
+            Some(updates) => {
+                t.release(*id);
+                results = updates.public;
+                private = updates.private;
+            }
+            None => return Err(Reason::Invalid),
+        },
+        TableUpdate::FromHand {
+            index,
+            top,
+            left,
+            face_up,
+        } => {
+            config.size.clamp(top, left);
+            match t.play_from_hand(*index, *top, *left, *face_up, player) {
+                Some(updates) => {
+                    results = updates.public;
+                    private = updates.private;
+                }
+                None => return Err(Reason::Invalid),
+            }
+        }
+        TableUpdate::Undo {} => results = t.undo(player, config, icons)?,
+        TableUpdate::Redo {} => results = t.redo(player, config, icons)?,
+        TableUpdate::Resync { version, epoch } => {
+            resync = t.resync(*epoch, *version, player.clone())
+        }
+        TableUpdate::PublicState { .. }
+        | TableUpdate::Snapshot { .. }
+        | TableUpdate::Hand { .. }
+        | TableUpdate::HandSize { .. }
+        | TableUpdate::Rolled { .. }
+        | TableUpdate::Deleted {}
+        | TableUpdate::Joined { .. }
+        | TableUpdate::Left { .. }
+        | TableUpdate::Rejected { .. }
+        | TableUpdate::Grabbed { .. }
+        | TableUpdate::CursorMoved { .. }
+        | TableUpdate::Pinged { .. }
+        | TableUpdate::Message { .. } => return Err(Reason::ServerOnly),
+        TableUpdate::Chat { text } => {
+            let message = t.say(player, text, config.chat_limit, config.chat_length)?;
+            results.push(TableUpdate::Message { message });
+            // Messages aren't part of the table's log, but they're saved with it
+            t.mark_dirty();
+        }
+        TableUpdate::DeleteMessage { id } => {
+            if !t.delete_message(*id) {
+                return Err(Reason::Invalid);
+            }
+            t.mark_dirty();
+        }
+        TableUpdate::Kick { player } => {
+            if !t.kick(player) {
+                return Err(Reason::Invalid);
+            }
+            // Their sockets are closed the next time they send anything
+            if let Some(left) = t.disconnect_player(player) {
+                results.extend(t.departed(left));
+            }
+        }
+        TableUpdate::Lock { locked } => t.set_locked(*locked),
+        TableUpdate::Listed { listed } => t.set_listed(*listed),
+        TableUpdate::TransferHost { player } => {
+            // Only someone at the table can be made host here, since they're known to exist.
+            // Anyone else has to be looked up by name, see `host::transfer_host`.
+            if !t.players().iter().any(|p| p.id == *player) || !t.transfer_host(player) {
+                return Err(Reason::Invalid);
+            }
+        }
+        TableUpdate::Rename { name } => {
+            if !t.rename(name) {
+                return Err(Reason::Invalid);
+            }
+            *name = t.name();
+        }
+        TableUpdate::Sharing { sharing } => {
+            if !sharing.take_invites().is_empty() {
+                // Inviting users requires the database, see `host::sharing`
+                return Err(Reason::Invalid);
+            }
+            t.set_sharing(sharing.clone());
+            // Don't send the password to everyone
+            *sharing = sharing.hidden();
+        }
+    }
+    if update.changes_table() {
+        if let Some(op) = op {
+            t.record(&player.id, op, limit);
+        }
+        t.log_update(player, (*update).clone(), results.clone());
+        t.mark_dirty();
+    }
+    let mut broadcasts = vec![];
+    if !update.replaced() && deferred.is_none() {
+        broadcasts.push((*update).clone());
+    }
+    broadcasts.extend(results);
+    resync.extend(private.into_iter().map(sync::Sequenced::from));
+    let flush = match (deferred, update.element_id()) {
+        (Some(Some(after)), Some(id)) => Some((coalesce::Preview::Element(id), after)),
+        _ => None,
+    };
+    Ok((t.sequence(broadcasts, config.resync_buffer), resync, flush))
 }
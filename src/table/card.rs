@@ -0,0 +1,116 @@
+use super::{ElementState, ItemState, Property, TableState, TableUpdate};
+
+// A card's own icon is its back, which everyone can see. The front is kept under `FACE`, in the
+// public state while the card is face up, and in the private state while it's face down, so
+// serializing the table never includes the front of a face down card.
+
+/// The face of a card or die that is shown to everyone
+pub(super) const FACE: &str = "face";
+/// Public state marking an element as a card, and whether it's face up
+const FACE_UP: &str = "face_up";
+
+impl ElementState {
+    pub(super) fn new_card(
+        id: u32,
+        icon_pack: u32,
+        icon_id: u32,
+        top: usize,
+        left: usize,
+        face: ItemState,
+        face_up: bool,
+    ) -> Self {
+        let el = Self::new(id, icon_pack, icon_id, top, left);
+        el.set_face(face, face_up);
+        el
+    }
+
+    fn is_card(&self) -> bool {
+        let guard = self.public_state.guard();
+        self.public_state.contains_key(FACE_UP, &guard)
+    }
+
+    fn set_face(&self, face: ItemState, face_up: bool) {
+        let public = self.public_state.guard();
+        let private = self.private_state.guard();
+        if face_up {
+            self.private_state.remove(FACE, &private);
+            self.public_state
+                .insert(FACE.into(), Property::Single(face), &public);
+        } else {
+            self.public_state.remove(FACE, &public);
+            self.private_state
+                .insert(FACE.into(), Property::Single(face), &private);
+        }
+        self.public_state.insert(
+            FACE_UP.into(),
+            Property::Single(ItemState::Num(face_up as usize)),
+            &public,
+        );
+    }
+
+    /// Turns a card over, returning false if this element isn't a card
+    fn flip(&self) -> bool {
+        if !self.is_card() {
+            return false;
+        }
+        let face = {
+            let public = self.public_state.guard();
+            let private = self.private_state.guard();
+            match (
+                self.public_state.get(FACE, &public),
+                self.private_state.get(FACE, &private),
+            ) {
+                (Some(Property::Single(face)), _) => Some((face.clone(), false)),
+                (_, Some(Property::Single(face))) => Some((face.clone(), true)),
+                _ => None,
+            }
+        };
+        match face {
+            Some((face, face_up)) => {
+                self.set_face(face, face_up);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl TableState {
+    /// Flips a card, returning the updates to send to everyone at the table
+    pub(super) fn flip(&self, id: u32) -> Option<Vec<TableUpdate>> {
+        let guard = self.elements.guard();
+        let card = self.elements.get(&id, &guard)?;
+        if !card.flip() {
+            return None;
+        }
+        Some(vec![TableUpdate::PublicState {
+            id,
+            public_state: card.public_state(),
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::serde_json;
+
+    use super::*;
+
+    #[test]
+    fn face_down_cards_are_hidden() {
+        let front = ItemState::Icon {
+            icon_pack: 7,
+            icon_id: 1234,
+        };
+        let card = ElementState::new_card(1, 7, 99, 0, 0, front, false);
+        let hidden = serde_json::to_string(&card).unwrap();
+        assert!(!hidden.contains("1234"));
+
+        assert!(card.flip());
+        let shown = serde_json::to_string(&card).unwrap();
+        assert!(shown.contains("1234"));
+
+        assert!(card.flip());
+        assert_eq!(serde_json::to_string(&card).unwrap(), hidden);
+    }
+}
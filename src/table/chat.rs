@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+use rocket::serde::{Deserialize, Serialize};
+
+use super::{reject::Reason, Player, TableState};
+
+// Players can chat over the table's websocket. The most recent messages are saved with the table,
+// so players joining later can see what they missed. The host can delete messages. Chat isn't
+// part of the table's log, so replays and undo skip it.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Message {
+    id: u32,
+    player: Player,
+    text: String,
+    sent: chrono::DateTime<Utc>,
+}
+
+/// The table's recent messages
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Chat {
+    messages: VecDeque<Message>,
+    /// Id of the next message
+    next_id: u32,
+}
+
+impl Chat {
+    /// Adds a message, forgetting the oldest ones once there's more than `limit`
+    fn push(&mut self, player: &Player, text: String, limit: usize) -> Message {
+        let message = Message {
+            id: self.next_id,
+            player: player.clone(),
+            text,
+            sent: Utc::now(),
+        };
+        self.next_id += 1;
+        self.messages.push_back(message.clone());
+        while self.messages.len() > limit {
+            self.messages.pop_front();
+        }
+        message
+    }
+}
+
+impl TableState {
+    /// Sends a message from the player. It's trimmed, and can't be empty or longer than `length`
+    /// characters.
+    pub(super) fn say(
+        &self,
+        player: &Player,
+        text: &str,
+        limit: usize,
+        length: usize,
+    ) -> Result<Message, Reason> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(Reason::Invalid);
+        }
+        if text.chars().count() > length {
+            return Err(Reason::TooLong);
+        }
+        Ok(self.chat.lock().unwrap().push(player, text.to_string(), limit))
+    }
+
+    /// Deletes a message. Returns whether it existed.
+    pub(super) fn delete_message(&self, id: u32) -> bool {
+        let mut chat = self.chat.lock().unwrap();
+        let before = chat.messages.len();
+        chat.messages.retain(|m| m.id != id);
+        chat.messages.len() != before
+    }
+
+    /// The table's recent messages, oldest first
+    pub(super) fn messages(&self) -> Vec<Message> {
+        self.chat.lock().unwrap().messages.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::PlayerId;
+
+    #[test]
+    fn history_is_bounded() {
+        let t = TableState::tester();
+        let alice = Player {
+            id: PlayerId::Guest("alice".into()),
+            name: "Alice".into(),
+        };
+        assert_eq!(t.say(&alice, "  ", 2, 10).unwrap_err(), Reason::Invalid);
+        assert_eq!(t.say(&alice, "far too long", 2, 10).unwrap_err(), Reason::TooLong);
+        for text in ["one", "two", "three"] {
+            assert!(t.say(&alice, text, 2, 10).is_ok());
+        }
+        let texts: Vec<_> = t.messages().into_iter().map(|m| m.text).collect();
+        assert_eq!(texts, ["two", "three"]);
+        assert!(t.delete_message(1));
+        assert!(!t.delete_message(1));
+        assert_eq!(t.messages().len(), 1);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use super::{sync::Sequenced, PlayerId, TableState, TableUpdate};
+
+// Updates that clients stream, like drag previews, are broadcast at most once per tick for each
+// key. The first one in a tick goes out straight away. Later ones replace each other, and whichever
+// is the latest when the tick ends is sent then, so the final position is never lost.
+
+#[derive(Debug)]
+pub(super) struct Tick {
+    /// When an update was last broadcast
+    sent: Instant,
+    /// The latest update since then
+    pending: Option<TableUpdate>,
+    /// Whether a connection is waiting to send the pending update
+    flushing: bool,
+}
+
+/// What to do with an update, see `offer`
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Offer {
+    /// Broadcast it now
+    Now,
+    /// Wait this long, then broadcast whatever is pending, see `TableState::flush_preview`
+    FlushAfter(Duration),
+    /// Someone else is already waiting to broadcast it
+    Pending,
+}
+
+/// Something that's coalesced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Preview {
+    /// Drag previews and moves of an element, see `drag`
+    Element(u32),
+    /// A player's cursor, see `pointer`
+    Cursor(PlayerId),
+}
+
+/// Decides when to broadcast an update. `rate` is the number of ticks per second.
+pub(super) fn offer<K: Hash + Eq>(
+    ticks: &mut HashMap<K, Tick>,
+    key: K,
+    update: TableUpdate,
+    rate: u32,
+    now: Instant,
+) -> Offer {
+    let tick = Duration::from_secs(1) / rate.max(1);
+    match ticks.get_mut(&key) {
+        Some(t) if t.flushing => {
+            t.pending = Some(update);
+            Offer::Pending
+        }
+        Some(t) if now.saturating_duration_since(t.sent) < tick => {
+            t.pending = Some(update);
+            t.flushing = true;
+            Offer::FlushAfter(tick - now.saturating_duration_since(t.sent))
+        }
+        _ => {
+            let t = Tick {
+                sent: now,
+                pending: None,
+                flushing: false,
+            };
+            ticks.insert(key, t);
+            Offer::Now
+        }
+    }
+}
+
+/// Takes the pending update, which has to be broadcast
+pub(super) fn flush<K: Hash + Eq>(
+    ticks: &mut HashMap<K, Tick>,
+    key: &K,
+    now: Instant,
+) -> Option<TableUpdate> {
+    let t = ticks.get_mut(key)?;
+    t.flushing = false;
+    let update = t.pending.take()?;
+    t.sent = now;
+    Some(update)
+}
+
+impl TableState {
+    /// Takes the latest update once its tick is over, ready to broadcast
+    pub(super) fn flush_preview(&self, key: &Preview, limit: usize) -> Option<Sequenced> {
+        let update = match key {
+            Preview::Element(id) => {
+                let update = flush(&mut self.drags.lock().unwrap(), id, Instant::now())?;
+                let guard = self.elements.guard();
+                if !self.elements.contains_key(id, &guard) {
+                    return None;
+                }
+                update
+            }
+            // Players' cursors are forgotten once they leave, so there's nothing to flush
+            Preview::Cursor(player) => {
+                flush(&mut self.cursors.lock().unwrap(), player, Instant::now())?
+            }
+        };
+        Some(match update {
+            // Moves change the table, so they're versioned like any other change
+            TableUpdate::Position { .. } => self.sequence(vec![update], limit).pop()?,
+            update => update.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_is_flushed() {
+        let mut ticks = HashMap::new();
+        let now = Instant::now();
+        let drag = |top| TableUpdate::Drag { id: 1, top, left: 0 };
+        assert_eq!(offer(&mut ticks, 1, drag(1), 10, now), Offer::Now);
+        let later = now + Duration::from_millis(40);
+        assert_eq!(
+            offer(&mut ticks, 1, drag(2), 10, later),
+            Offer::FlushAfter(Duration::from_millis(60))
+        );
+        assert_eq!(offer(&mut ticks, 1, drag(3), 10, later), Offer::Pending);
+        let flushed = flush(&mut ticks, &1, now + Duration::from_millis(100));
+        assert!(matches!(flushed, Some(TableUpdate::Drag { top: 3, .. })));
+        assert!(flush(&mut ticks, &1, now + Duration::from_millis(100)).is_none());
+        assert_eq!(
+            offer(&mut ticks, 1, drag(4), 10, now + Duration::from_millis(200)),
+            Offer::Now
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use rand::{seq::SliceRandom, Rng};
+use rocket::serde::Deserialize;
+
+/// Characters that can't be confused with each other, i.e. no `0`/`O` or `1`/`I`/`L`
+const DEFAULT_ALPHABET: &str = "23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+
+/// Short, easy to spell words for word codes
+const WORDS: &[&str] = &[
+    "ACORN", "ANCHOR", "APPLE", "ARROW", "BADGER", "BASIL", "BEACON", "BISON", "BRAVE", "BREEZE",
+    "CACTUS", "CAMEL", "CANDLE", "CASTLE", "CEDAR", "CHERRY", "CLOVER", "COBALT", "COMET", "CORAL",
+    "COYOTE", "CRANE", "DAISY", "DELTA", "DESERT", "DRAGON", "EAGLE", "EMBER", "FALCON", "FERN",
+    "FJORD", "FOREST", "FOX", "GARNET", "GECKO", "GINGER", "GLACIER", "GOBLIN", "GRAPE", "HARBOR",
+    "HAZEL", "HERON", "HONEY", "ISLAND", "IVORY", "JADE", "JASPER", "JUNGLE", "KETTLE", "KNIGHT",
+    "LANTERN", "LEMON", "LOTUS", "MAGPIE", "MAPLE", "MEADOW", "MESA", "MOOSE", "NECTAR", "NOMAD",
+    "OCEAN", "ORCHID", "OTTER", "PANDA", "PEPPER", "PIRATE", "PLUM", "PRAIRIE", "QUARTZ", "RAVEN",
+    "RIVER", "ROBIN", "SAFFRON", "SALMON", "SPARROW", "SPRUCE", "SUMMIT", "THUNDER", "TIGER",
+    "TOPAZ", "TULIP", "TUNDRA", "VELVET", "VIOLET", "WALNUT", "WILLOW", "WIZARD", "ZEPHYR",
+];
+
+/// How new table codes are generated
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct CodeConfig {
+    /// Number of characters in a code
+    length: usize,
+    /// Characters codes are made from. Codes are case insensitive, so lowercase letters are the
+    /// same as uppercase ones.
+    alphabet: String,
+    /// Use words separated by dashes, instead of random characters
+    words: bool,
+    /// Number of words in a word code
+    word_count: usize,
+}
+
+impl Default for CodeConfig {
+    fn default() -> Self {
+        Self {
+            length: 6,
+            alphabet: DEFAULT_ALPHABET.into(),
+            words: false,
+            word_count: 3,
+        }
+    }
+}
+
+impl CodeConfig {
+    /// Generates a new random code, which is already normalized
+    pub fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        if self.words {
+            let words: Vec<_> = (0..self.word_count.max(1))
+                .map(|_| *WORDS.choose(&mut rng).expect("Word list is not empty"))
+                .collect();
+            words.join("-")
+        } else {
+            let alphabet = self.alphabet();
+            (0..self.length.max(1))
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect()
+        }
+    }
+
+    fn alphabet(&self) -> Vec<char> {
+        let mut alphabet: Vec<char> = normalize(&self.alphabet)
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        if alphabet.is_empty() {
+            DEFAULT_ALPHABET.chars().collect()
+        } else {
+            alphabet
+        }
+    }
+}
+
+/// Converts a code entered by a user into the form used to store tables
+pub fn normalize(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_use_alphabet() {
+        let config = CodeConfig {
+            alphabet: "ab".into(),
+            ..CodeConfig::default()
+        };
+        let code = config.generate();
+        assert_eq!(code.len(), config.length);
+        assert!(code.chars().all(|c| c == 'A' || c == 'B'));
+        assert_eq!(normalize(&code), code);
+    }
+
+    #[test]
+    fn word_codes() {
+        let config = CodeConfig {
+            words: true,
+            ..CodeConfig::default()
+        };
+        let code = config.generate();
+        assert_eq!(code.split('-').count(), config.word_count);
+        assert_eq!(normalize(&code.to_lowercase()), code);
+    }
+}
@@ -0,0 +1,302 @@
+use std::sync::atomic::Ordering;
+
+use rand::{seq::SliceRandom, Rng};
+
+use super::{
+    hand::HandUpdates, Action, ElementState, ItemState, PlayerId, Property, TableState,
+    TableUpdate,
+};
+
+// A deck is an element with a `Draw` action. The action names the stack of cards in the
+// private state, which is drawn from the end. The deck also keeps every card it started with, so
+// it can be reset, and the ids of the elements drawn from it, so they can be picked back up.
+//
+// Cards are drawn face down, with the deck's icon as their back. Each one remembers its deck, so
+// a card that's been deleted can't be put back once the deck has been reset. Cards keep their deck
+// while they're in a hand, see `hand`, so resetting the deck takes those back too.
+
+/// Action clients use to draw from a deck
+pub(super) const DRAW: &str = "draw";
+const CARDS: &str = "cards";
+/// Private state of a deck, with every card it started with
+pub(super) const ALL: &str = "all";
+const DRAWN: &str = "drawn";
+/// Private state of a drawn card, with the id of the deck it came from
+const DECK: &str = "deck";
+/// Public state with the number of cards left in a deck
+const COUNT: &str = "count";
+
+/// How far from the deck drawn cards are placed
+const DRAW_OFFSET: usize = 80;
+
+impl ElementState {
+    pub(super) fn new_deck(
+        id: u32,
+        icon_pack: u32,
+        icon_id: u32,
+        top: usize,
+        left: usize,
+        cards: Vec<ItemState>,
+    ) -> Self {
+        let el = Self::new(id, icon_pack, icon_id, top, left);
+        {
+            let guard = el.private_state.guard();
+            el.private_state
+                .insert(CARDS.into(), Property::Stack(cards.clone()), &guard);
+            el.private_state
+                .insert(ALL.into(), Property::Stack(cards), &guard);
+            el.private_state
+                .insert(DRAWN.into(), Property::Stack(vec![]), &guard);
+        }
+        {
+            let guard = el.action.guard();
+            el.action
+                .insert(DRAW.into(), Action::Draw(CARDS.into()), &guard);
+        }
+        el.update_count(CARDS);
+        el
+    }
+
+    /// The private state key holding the cards, if this element is a deck
+    fn deck_key(&self) -> Option<String> {
+        let guard = self.action.guard();
+        match self.action.get(DRAW, &guard) {
+            Some(Action::Draw(key)) => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    /// Takes the top card off the deck
+    pub(super) fn draw(&self) -> Option<ItemState> {
+        let key = self.deck_key()?;
+        let card = self.modify_stack(&key, |cards| cards.pop()).flatten()?;
+        self.update_count(&key);
+        Some(card)
+    }
+
+    /// Runs `f` on a stack in the private state, and stores the result
+    pub(super) fn modify_stack<R>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut Vec<ItemState>) -> R,
+    ) -> Option<R> {
+        let guard = self.private_state.guard();
+        let mut ret = None;
+        self.private_state.compute_if_present(
+            key,
+            |_key, prop| match prop {
+                Property::Stack(items) => {
+                    let mut items = items.clone();
+                    ret = Some(f(&mut items));
+                    Some(Property::Stack(items))
+                }
+                prop => Some(prop.clone()),
+            },
+            &guard,
+        );
+        ret
+    }
+
+    pub(super) fn get_stack(&self, key: &str) -> Option<Vec<ItemState>> {
+        let guard = self.private_state.guard();
+        match self.private_state.get(key, &guard) {
+            Some(Property::Stack(items)) => Some(items.clone()),
+            _ => None,
+        }
+    }
+
+    /// Remembers that a card came from this deck, so resetting the deck takes it back
+    pub(super) fn add_drawn(&self, card: &ElementState) {
+        {
+            let guard = card.private_state.guard();
+            card.private_state.insert(
+                DECK.into(),
+                Property::Single(ItemState::Num(self.element_id as usize)),
+                &guard,
+            );
+        }
+        let card_id = card.element_id;
+        self.modify_stack(DRAWN, |drawn| drawn.push(ItemState::Num(card_id as usize)));
+    }
+
+    /// The deck a card was drawn from
+    pub(super) fn drawn_from(&self) -> Option<u32> {
+        let guard = self.private_state.guard();
+        match self.private_state.get(DECK, &guard) {
+            Some(Property::Single(ItemState::Num(id))) => Some(*id as u32),
+            _ => None,
+        }
+    }
+
+    fn update_count(&self, key: &str) {
+        let count = self.get_stack(key).map_or(0, |cards| cards.len());
+        let guard = self.public_state.guard();
+        self.public_state.insert(
+            COUNT.into(),
+            Property::Single(ItemState::Num(count)),
+            &guard,
+        );
+    }
+}
+
+impl TableState {
+    /// Whether a removed element can be put back on the table. Cards drawn from a deck can't be
+    /// once the deck has taken them back, since their face is in the deck again.
+    pub(super) fn can_restore(&self, el: &ElementState) -> bool {
+        let deck_id = match el.drawn_from() {
+            Some(id) => id,
+            None => return true,
+        };
+        let el_guard = self.elements.guard();
+        match self.elements.get(&deck_id, &el_guard) {
+            Some(deck) => deck
+                .get_stack(DRAWN)
+                .map_or(false, |drawn| drawn.contains(&ItemState::Num(el.element_id as usize))),
+            // The deck and its cards are gone
+            None => true,
+        }
+    }
+
+    /// Runs a deck action for `player`, returning the updates to send
+    pub(super) fn deck_action(&self, id: u32, act: &str, player: &PlayerId) -> Option<HandUpdates> {
+        let el_guard = self.elements.guard();
+        let deck = self.elements.get(&id, &el_guard)?;
+        let key = deck.deck_key()?;
+        let mut updates = vec![];
+        let mut private = vec![];
+        match act {
+            DRAW => {
+                let face = deck.draw()?;
+                let card_id = self.next_id();
+                let top = deck.top.load(Ordering::Acquire);
+                let left = deck.left.load(Ordering::Acquire) + DRAW_OFFSET;
+                let card = ElementState::new_card(
+                    card_id,
+                    deck.icon_pack,
+                    deck.icon_id,
+                    top,
+                    left,
+                    face,
+                    false,
+                );
+                deck.add_drawn(&card);
+                let public_state = card.public_state();
+                self.elements.insert(card_id, card, &el_guard);
+                updates.push(TableUpdate::ElementCreate {
+                    icon_pack: deck.icon_pack,
+                    icon_id: deck.icon_id,
+                    id: card_id,
+                    top,
+                    left,
+                });
+                updates.push(TableUpdate::PublicState {
+                    id: card_id,
+                    public_state,
+                });
+            }
+            "shuffle" => {
+                deck.modify_stack(&key, |cards| cards.shuffle(&mut rand::thread_rng()))?;
+            }
+            "cut" => {
+                deck.modify_stack(&key, |cards| {
+                    if cards.len() > 1 {
+                        let at = rand::thread_rng().gen_range(1..cards.len());
+                        cards.rotate_left(at);
+                    }
+                })?;
+            }
+            "reset" => {
+                let all = deck.get_stack(ALL)?;
+                deck.modify_stack(&key, move |cards| *cards = all)?;
+                let drawn = deck.modify_stack(DRAWN, std::mem::take).unwrap_or_default();
+                for card in drawn {
+                    if let ItemState::Num(card_id) = card {
+                        let card_id = card_id as u32;
+                        if self.elements.remove(&card_id, &el_guard).is_some() {
+                            updates.push(TableUpdate::ElementDelete { id: card_id });
+                        }
+                    }
+                }
+                let hands = self.return_to_deck(id, player);
+                updates.extend(hands.public);
+                private = hands.private;
+            }
+            _ => return None,
+        }
+        deck.update_count(&key);
+        updates.push(TableUpdate::PublicState {
+            id,
+            public_state: deck.public_state(),
+        });
+        Some(HandUpdates {
+            public: updates,
+            private,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deck(t: &TableState, size: usize) -> u32 {
+        let id = t.next_id();
+        let cards = (0..size).map(ItemState::Num).collect();
+        let deck = ElementState::new_deck(id, 1, 1, 0, 0, cards);
+        t.elements.insert(id, deck, &t.elements.guard());
+        id
+    }
+
+    fn cards(t: &TableState, id: u32) -> Vec<ItemState> {
+        let guard = t.elements.guard();
+        t.elements.get(&id, &guard).unwrap().get_stack(CARDS).unwrap()
+    }
+
+    #[test]
+    fn draw_until_empty() {
+        let t = TableState::tester();
+        let alice = PlayerId::Guest("alice".into());
+        let id = deck(&t, 2);
+        for _ in 0..2 {
+            let updates = t.deck_action(id, DRAW, &alice).unwrap();
+            assert!(matches!(updates.public[0], TableUpdate::ElementCreate { .. }));
+        }
+        assert!(cards(&t, id).is_empty());
+        assert!(t.deck_action(id, DRAW, &alice).is_none());
+        // Only decks can be drawn from
+        assert!(t.deck_action(1, DRAW, &alice).is_none());
+    }
+
+    #[test]
+    fn shuffle_and_cut_keep_the_cards() {
+        let t = TableState::tester();
+        let alice = PlayerId::Guest("alice".into());
+        let id = deck(&t, 10);
+        t.deck_action(id, "shuffle", &alice).unwrap();
+        t.deck_action(id, "cut", &alice).unwrap();
+        let mut shuffled = cards(&t, id);
+        shuffled.sort();
+        assert_eq!(shuffled, (0..10).map(ItemState::Num).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reset_restores_the_deck() {
+        let t = TableState::tester();
+        let alice = PlayerId::Guest("alice".into());
+        let id = deck(&t, 3);
+        let drawn: Vec<_> = (0..2)
+            .filter_map(|_| match t.deck_action(id, DRAW, &alice)?.public[0] {
+                TableUpdate::ElementCreate { id: card, .. } => Some(card),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(drawn.len(), 2);
+        // A drawn card that's been deleted can't be put back once the deck has it again
+        let deleted = t.elements.remove(&drawn[0], &t.elements.guard()).unwrap().clone();
+        assert!(t.can_restore(&deleted));
+        t.deck_action(id, "reset", &alice).unwrap();
+        assert_eq!(cards(&t, id).len(), 3);
+        assert!(!t.elements.contains_key(&drawn[1], &t.elements.guard()));
+        assert!(!t.can_restore(&deleted));
+    }
+}
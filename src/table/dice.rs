@@ -0,0 +1,130 @@
+use rand::{rngs::OsRng, seq::SliceRandom};
+use rocket::serde::{Deserialize, Serialize};
+
+use super::{
+    card::FACE, Action, ElementState, ItemState, Player, Property, TableState, TableUpdate,
+};
+
+// Dice (and spinners, or anything else that lands on a random face) are elements with a `Select`
+// action. The action names the list of faces in the private state, and rolling picks one of them
+// and shows it in the public state. Unlike decks, the faces are never used up.
+
+/// Action clients use to roll a die
+pub(super) const ROLL: &str = "roll";
+const FACES: &str = "faces";
+
+/// The face a single die landed on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct DiceRoll {
+    id: u32,
+    face: ItemState,
+}
+
+impl ElementState {
+    pub(super) fn new_dice(
+        id: u32,
+        icon_pack: u32,
+        icon_id: u32,
+        top: usize,
+        left: usize,
+        faces: Vec<ItemState>,
+    ) -> Self {
+        let el = Self::new(id, icon_pack, icon_id, top, left);
+        if let Some(face) = faces.first() {
+            let guard = el.public_state.guard();
+            el.public_state
+                .insert(FACE.into(), Property::Single(face.clone()), &guard);
+        }
+        {
+            let guard = el.private_state.guard();
+            el.private_state
+                .insert(FACES.into(), Property::Stack(faces), &guard);
+        }
+        {
+            let guard = el.action.guard();
+            el.action
+                .insert(ROLL.into(), Action::Select(FACES.into()), &guard);
+        }
+        el
+    }
+
+    /// Picks a random face, if this element is a die
+    fn roll(&self) -> Option<ItemState> {
+        let key = {
+            let guard = self.action.guard();
+            match self.action.get(ROLL, &guard) {
+                Some(Action::Select(key)) => key.clone(),
+                _ => return None,
+            }
+        };
+        // The OS rng is used so players can't predict rolls
+        let face = self.get_stack(&key)?.choose(&mut OsRng)?.clone();
+        let guard = self.public_state.guard();
+        self.public_state
+            .insert(FACE.into(), Property::Single(face.clone()), &guard);
+        Some(face)
+    }
+}
+
+impl TableState {
+    /// Rolls every die in `ids`, returning the updates to send to everyone at the table. Anything
+    /// that isn't a die is ignored, but at least one die has to be rolled.
+    pub(super) fn roll(&self, ids: &[u32], player: &Player) -> Option<Vec<TableUpdate>> {
+        let guard = self.elements.guard();
+        let mut rolls = vec![];
+        let mut updates = vec![];
+        for &id in ids {
+            let die = match self.elements.get(&id, &guard) {
+                Some(die) => die,
+                None => continue,
+            };
+            if let Some(face) = die.roll() {
+                rolls.push(DiceRoll { id, face });
+                updates.push(TableUpdate::PublicState {
+                    id,
+                    public_state: die.public_state(),
+                });
+            }
+        }
+        if rolls.is_empty() {
+            return None;
+        }
+        updates.push(TableUpdate::Rolled {
+            player: player.clone(),
+            rolls,
+        });
+        Some(updates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::PlayerId;
+
+    #[test]
+    fn rolls_listed_faces() {
+        let t = TableState::tester();
+        let alice = Player {
+            id: PlayerId::Guest("alice".into()),
+            name: "Alice".into(),
+        };
+        let faces = vec![ItemState::Num(2), ItemState::Num(4)];
+        let id = t.next_id();
+        let die = ElementState::new_dice(id, 1, 1, 0, 0, faces.clone());
+        t.elements.insert(id, die, &t.elements.guard());
+        for _ in 0..20 {
+            let updates = t.roll(&[id, 1], &alice).unwrap();
+            match updates.last() {
+                Some(TableUpdate::Rolled { rolls, .. }) => {
+                    // Element 1 isn't a die, so it's skipped
+                    assert_eq!(rolls.len(), 1);
+                    assert!(faces.contains(&rolls[0].face));
+                }
+                _ => panic!("Expected a roll"),
+            }
+        }
+        assert!(t.roll(&[1], &alice).is_none());
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Instant;
+
+use super::{
+    coalesce::{self, Offer},
+    TableState, TableUpdate,
+};
+
+// While an element is being dragged, clients stream `Drag` previews so everyone can watch it move.
+// Previews aren't saved, logged or versioned, and they're coalesced so at most one per element is
+// broadcast each tick, see `coalesce`. The element only moves once the drag ends, with a normal
+// `Position` update, which shares the element's tick so it replaces any preview still waiting.
+
+impl TableState {
+    /// Decides when to broadcast a drag preview or move of the element. `rate` is the number of
+    /// ticks per second.
+    pub(super) fn drag_tick(&self, id: u32, update: TableUpdate, rate: u32) -> Offer {
+        self.drag_tick_at(id, update, rate, Instant::now())
+    }
+
+    fn drag_tick_at(&self, id: u32, update: TableUpdate, rate: u32, now: Instant) -> Offer {
+        coalesce::offer(&mut self.drags.lock().unwrap(), id, update, rate, now)
+    }
+
+    /// Forgets about a drag once the element is gone, dropping anything waiting to be sent
+    pub(super) fn end_drag(&self, id: u32) {
+        self.drags.lock().unwrap().remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn one_preview_per_tick() {
+        let t = TableState::tester();
+        let now = Instant::now();
+        let drag = |id| TableUpdate::Drag { id, top: 0, left: 0 };
+        assert_eq!(t.drag_tick_at(1, drag(1), 10, now), Offer::Now);
+        let later = now + Duration::from_millis(50);
+        assert_ne!(t.drag_tick_at(1, drag(1), 10, later), Offer::Now);
+        // Other elements have their own ticks
+        assert_eq!(t.drag_tick_at(2, drag(2), 10, later), Offer::Now);
+        t.end_drag(1);
+        assert_eq!(t.drag_tick_at(1, drag(1), 10, later), Offer::Now);
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use rocket::serde::Serialize;
+
+use super::{reject::Reason, Player, PlayerId, TableState};
+
+// A player grabs an element before dragging it, so nobody else can move it at the same time.
+// Grabs are short lived: dragging the element keeps it held, and it's released when the drag
+// ends. Everything a player is holding is released when their last connection closes.
+
+#[derive(Debug, Clone)]
+pub(super) struct Grab {
+    player: Player,
+    until: Instant,
+}
+
+/// An element someone is holding, as sent to clients
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Held {
+    id: u32,
+    player: Player,
+    /// Milliseconds until the grab expires, unless it's refreshed
+    expires_in: u64,
+}
+
+impl TableState {
+    /// Grabs an element for the player, or refreshes their grab. Fails if someone else is holding
+    /// it.
+    pub(super) fn grab(&self, id: u32, player: &Player, ttl: u64) -> Result<Held, Reason> {
+        self.check_grab(id, player)?;
+        let ttl = Duration::from_secs(ttl);
+        self.grabs.lock().unwrap().insert(
+            id,
+            Grab {
+                player: player.clone(),
+                until: Instant::now() + ttl,
+            },
+        );
+        Ok(Held {
+            id,
+            player: player.clone(),
+            expires_in: ttl.as_millis() as u64,
+        })
+    }
+
+    /// Makes sure nobody else is holding the element
+    pub(super) fn check_grab(&self, id: u32, player: &Player) -> Result<(), Reason> {
+        let mut grabs = self.grabs.lock().unwrap();
+        match grabs.get(&id) {
+            Some(grab) if grab.until <= Instant::now() => {
+                grabs.remove(&id);
+                Ok(())
+            }
+            Some(grab) if grab.player.id != player.id => Err(Reason::Grabbed),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lets go of an element, whoever was holding it. Returns whether it was held.
+    pub(super) fn release(&self, id: u32) -> bool {
+        self.grabs.lock().unwrap().remove(&id).is_some()
+    }
+
+    /// Lets go of everything the player is holding, returning the elements they held
+    pub(super) fn release_all(&self, player: &PlayerId) -> Vec<u32> {
+        let mut released = vec![];
+        self.grabs.lock().unwrap().retain(|&id, grab| {
+            if grab.player.id == *player {
+                released.push(id);
+                false
+            } else {
+                true
+            }
+        });
+        released.sort_unstable();
+        released
+    }
+
+    /// Every element someone is holding
+    pub(super) fn held(&self) -> Vec<Held> {
+        let now = Instant::now();
+        let mut grabs = self.grabs.lock().unwrap();
+        grabs.retain(|_, grab| grab.until > now);
+        grabs
+            .iter()
+            .map(|(&id, grab)| Held {
+                id,
+                player: grab.player.clone(),
+                expires_in: (grab.until - now).as_millis() as u64,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(id: &str) -> Player {
+        Player {
+            id: PlayerId::Guest(id.into()),
+            name: id.into(),
+        }
+    }
+
+    #[test]
+    fn only_the_holder_can_move() {
+        let t = TableState::tester();
+        let (alice, bob) = (player("alice"), player("bob"));
+        assert!(t.grab(1, &alice, 10).is_ok());
+        assert_eq!(t.check_grab(1, &bob).unwrap_err(), Reason::Grabbed);
+        assert!(t.check_grab(1, &alice).is_ok());
+        assert!(t.release(1));
+        assert!(t.grab(1, &bob, 0).is_ok());
+        // Grabs that have expired don't stop anyone
+        assert!(t.check_grab(1, &alice).is_ok());
+        for id in [3, 2] {
+            assert!(t.grab(id, &alice, 10).is_ok());
+        }
+        assert_eq!(t.release_all(&alice.id), [2, 3]);
+        assert!(t.release_all(&alice.id).is_empty());
+        assert!(t.check_grab(1, &alice).is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use rocket::serde::{Deserialize, Serialize};
+
+use super::{
+    card::FACE, ElementState, ItemState, Player, PlayerId, Property, TableState, TableUpdate,
+};
+
+// Each player has a hand of cards that only they can see. Cards in a hand aren't elements, so
+// they aren't part of `elements` and are never broadcast. The owner is sent their whole hand
+// whenever it changes, and everyone else is only told how many cards are in it. The hand is only
+// sent on the connection that changed it, so the owner's other connections ask for it when the
+// count doesn't match what they have.
+
+/// Action clients use to draw from a deck into their hand
+pub(super) const DRAW_HAND: &str = "draw_hand";
+
+/// A card held in a hand, along with the back it's shown with once played
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct HandCard {
+    icon_pack: u32,
+    icon_id: u32,
+    face: ItemState,
+    /// The deck the card was drawn from, which takes it back when it's reset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deck: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Hand {
+    player: Player,
+    cards: Vec<HandCard>,
+}
+
+impl Hand {
+    pub(super) fn owner(&self) -> &PlayerId {
+        &self.player.id
+    }
+
+    /// Every icon in the hand, as `(icon_pack, icon_id)`
+    pub(super) fn icons(&self) -> Vec<(u32, u32)> {
+        self.cards
+            .iter()
+            .flat_map(|card| {
+                std::iter::once((card.icon_pack, card.icon_id)).chain(card.face.icon())
+            })
+            .collect()
+    }
+
+    pub(super) fn remap_icon_packs(&mut self, ids: &HashMap<u32, u32>) {
+        for card in &mut self.cards {
+            card.icon_pack = ids.get(&card.icon_pack).copied().unwrap_or(card.icon_pack);
+            card.face.remap_icon_packs(ids);
+        }
+    }
+}
+
+/// How many cards someone else is holding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct HandSize {
+    player: Player,
+    count: usize,
+}
+
+/// Updates to send after changing a hand
+pub(super) struct HandUpdates {
+    /// Sent to everyone at the table
+    pub public: Vec<TableUpdate>,
+    /// Only sent to the owner of the hand
+    pub private: Vec<TableUpdate>,
+}
+
+impl TableState {
+    /// The cards in a player's hand
+    pub(super) fn hand(&self, player: &PlayerId) -> Vec<HandCard> {
+        let guard = self.hands.guard();
+        self.hands
+            .get(player, &guard)
+            .map(|h| h.cards.clone())
+            .unwrap_or_default()
+    }
+
+    /// The number of cards in everyone's hand
+    pub(super) fn hand_sizes(&self) -> Vec<HandSize> {
+        let guard = self.hands.guard();
+        self.hands
+            .values(&guard)
+            .map(|h| HandSize {
+                player: h.player.clone(),
+                count: h.cards.len(),
+            })
+            .collect()
+    }
+
+    /// Runs `f` on a player's hand, creating it if needed. Nothing changes if `f` returns none.
+    fn modify_hand<R>(
+        &self,
+        player: &Player,
+        f: impl FnOnce(&mut Vec<HandCard>) -> Option<R>,
+    ) -> Option<(R, HandUpdates)> {
+        let guard = self.hands.guard();
+        let empty = Hand {
+            player: player.clone(),
+            cards: vec![],
+        };
+        // Ignore the error if they already have a hand
+        let _ = self.hands.try_insert(player.id.clone(), empty, &guard);
+        // The change is made in place, so concurrent changes to the same hand can't be lost
+        let mut ret = None;
+        self.hands.compute_if_present(
+            &player.id,
+            |_id, hand| {
+                let mut changed = hand.clone();
+                match f(&mut changed.cards) {
+                    Some(r) => {
+                        let updates = HandUpdates {
+                            public: vec![TableUpdate::HandSize {
+                                player: player.clone(),
+                                count: changed.cards.len(),
+                            }],
+                            private: vec![TableUpdate::Hand {
+                                cards: changed.cards.clone(),
+                            }],
+                        };
+                        ret = Some((r, updates));
+                        Some(changed)
+                    }
+                    // Returning none would remove the hand
+                    None => Some(hand.clone()),
+                }
+            },
+            &guard,
+        );
+        ret
+    }
+
+    /// Draws the top card of a deck into the player's hand
+    pub(super) fn draw_to_hand(&self, id: u32, player: &Player) -> Option<HandUpdates> {
+        let el_guard = self.elements.guard();
+        let deck = self.elements.get(&id, &el_guard)?;
+        let face = deck.draw()?;
+        let card = HandCard {
+            icon_pack: deck.icon_pack,
+            icon_id: deck.icon_id,
+            face,
+            deck: Some(id),
+        };
+        let ((), mut updates) = self.modify_hand(player, |cards| {
+            cards.push(card);
+            Some(())
+        })?;
+        updates.public.push(TableUpdate::PublicState {
+            id,
+            public_state: deck.public_state(),
+        });
+        Some(updates)
+    }
+
+    /// Picks an element up off the table and puts it in the player's hand
+    pub(super) fn take_into_hand(&self, id: u32, player: &Player) -> Option<HandUpdates> {
+        let el_guard = self.elements.guard();
+        let card = {
+            let el = self.elements.get(&id, &el_guard)?;
+            if el.has_actions() {
+                // Decks and dice can't be held
+                return None;
+            }
+            HandCard {
+                icon_pack: el.icon_pack,
+                icon_id: el.icon_id,
+                face: el.face().unwrap_or(ItemState::Icon {
+                    icon_pack: el.icon_pack,
+                    icon_id: el.icon_id,
+                }),
+                deck: el.drawn_from(),
+            }
+        };
+        self.elements.remove(&id, &el_guard)?;
+        let ((), mut updates) = self.modify_hand(player, |cards| {
+            cards.push(card);
+            Some(())
+        })?;
+        updates.public.insert(0, TableUpdate::ElementDelete { id });
+        Some(updates)
+    }
+
+    /// Plays a card from the player's hand onto the table
+    pub(super) fn play_from_hand(
+        &self,
+        index: usize,
+        top: usize,
+        left: usize,
+        face_up: bool,
+        player: &Player,
+    ) -> Option<HandUpdates> {
+        // A bad index leaves the hand alone
+        let (card, mut updates) = self.modify_hand(player, |cards| {
+            if index < cards.len() {
+                Some(cards.remove(index))
+            } else {
+                None
+            }
+        })?;
+        let id = self.next_id();
+        let el = ElementState::new_card(
+            id,
+            card.icon_pack,
+            card.icon_id,
+            top,
+            left,
+            card.face,
+            face_up,
+        );
+        updates.public.push(TableUpdate::ElementCreate {
+            icon_pack: card.icon_pack,
+            icon_id: card.icon_id,
+            id,
+            top,
+            left,
+        });
+        updates.public.push(TableUpdate::PublicState {
+            id,
+            public_state: el.public_state(),
+        });
+        let el_guard = self.elements.guard();
+        if let Some(deck) = card.deck.and_then(|deck| self.elements.get(&deck, &el_guard)) {
+            deck.add_drawn(&el);
+        }
+        self.elements.insert(id, el, &el_guard);
+        Some(updates)
+    }
+
+    /// Takes every card drawn from the deck out of everyone's hands, e.g. when it's reset. Only
+    /// `player` is sent their new hand, the other owners ask for theirs once the count changes.
+    pub(super) fn return_to_deck(&self, deck: u32, player: &PlayerId) -> HandUpdates {
+        let owners: Vec<Player> = {
+            let guard = self.hands.guard();
+            self.hands
+                .values(&guard)
+                .filter(|h| h.cards.iter().any(|c| c.deck == Some(deck)))
+                .map(|h| h.player.clone())
+                .collect()
+        };
+        let mut updates = HandUpdates {
+            public: vec![],
+            private: vec![],
+        };
+        for owner in owners {
+            let returned = self.modify_hand(&owner, |cards| {
+                let before = cards.len();
+                cards.retain(|c| c.deck != Some(deck));
+                (cards.len() != before).then_some(())
+            });
+            if let Some(((), hand)) = returned {
+                updates.public.extend(hand.public);
+                if owner.id == *player {
+                    updates.private.extend(hand.private);
+                }
+            }
+        }
+        updates
+    }
+}
+
+impl ElementState {
+    fn has_actions(&self) -> bool {
+        !self.action.is_empty()
+    }
+
+    /// The front of a card, whether it's face up or not
+    fn face(&self) -> Option<ItemState> {
+        let public = self.public_state.guard();
+        let private = self.private_state.guard();
+        match self
+            .public_state
+            .get(FACE, &public)
+            .or_else(|| self.private_state.get(FACE, &private))
+        {
+            Some(Property::Single(face)) => Some(face.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(name: &str) -> Player {
+        Player {
+            id: PlayerId::Guest(name.into()),
+            name: name.into(),
+        }
+    }
+
+    #[test]
+    fn reset_takes_back_held_cards() {
+        let t = TableState::tester();
+        let (alice, bob) = (player("alice"), player("bob"));
+        let id = t.next_id();
+        let cards = (1..=3).map(ItemState::Num).collect();
+        let deck = ElementState::new_deck(id, 1, 1, 0, 0, cards);
+        t.elements.insert(id, deck, &t.elements.guard());
+        t.draw_to_hand(id, &alice).unwrap();
+        t.draw_to_hand(id, &bob).unwrap();
+        t.draw_to_hand(id, &bob).unwrap();
+        // Cards played from a hand still belong to the deck
+        let played = t.play_from_hand(0, 0, 0, true, &bob).unwrap();
+        let played = played.public.iter().find_map(|u| match u {
+            TableUpdate::ElementCreate { id, .. } => Some(*id),
+            _ => None,
+        });
+        let reset = t.deck_action(id, "reset", &alice.id).unwrap();
+        assert!(t.hand(&alice.id).is_empty());
+        assert!(t.hand(&bob.id).is_empty());
+        assert!(!t.elements.contains_key(&played.unwrap(), &t.elements.guard()));
+        // Only whoever reset the deck is sent their hand, everyone sees the new sizes
+        assert_eq!(reset.private.len(), 1);
+        let sizes = reset.public.iter();
+        assert_eq!(sizes.filter(|u| matches!(u, TableUpdate::HandSize { .. })).count(), 2);
+        let deck = t.elements.get(&id, &t.elements.guard()).unwrap().get_stack("cards");
+        assert_eq!(deck.map(|cards| cards.len()), Some(3));
+    }
+}
@@ -0,0 +1,284 @@
+use std::{collections::VecDeque, sync::atomic::Ordering};
+
+use super::{
+    limits::IconIndex, reject::Reason, ElementState, ItemState, Player, PlayerId, TableConfig,
+    TableState, TableUpdate,
+};
+
+// Undo and redo work on a bounded log of operations. Each entry records who made the change, and
+// enough to reverse it. Players can undo their own changes, while the host can undo anyone's.
+// The log only lives in memory, so it's lost when the server restarts.
+//
+// Putting an element back goes through the same checks as creating one, since the table may have
+// changed since it was removed.
+
+/// A change to the table that can be reversed
+#[derive(Debug, Clone)]
+pub(super) enum Op {
+    Create(ElementState),
+    Delete(ElementState),
+    Move {
+        id: u32,
+        from: (usize, usize),
+        to: (usize, usize),
+    },
+}
+
+impl Op {
+    fn inverse(self) -> Self {
+        match self {
+            Self::Create(el) => Self::Delete(el),
+            Self::Delete(el) => Self::Create(el),
+            Self::Move { id, from, to } => Self::Move {
+                id,
+                from: to,
+                to: from,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    player: PlayerId,
+    op: Op,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct History {
+    undo: VecDeque<Entry>,
+    redo: VecDeque<Entry>,
+}
+
+impl History {
+    /// The most recent entry the player is allowed to reverse
+    fn find(list: &VecDeque<Entry>, player: &PlayerId, any: bool) -> Option<usize> {
+        list.iter().rposition(|e| any || &e.player == player)
+    }
+
+    fn push(list: &mut VecDeque<Entry>, entry: Entry, limit: usize) {
+        list.push_back(entry);
+        while list.len() > limit {
+            list.pop_front();
+        }
+    }
+}
+
+impl TableState {
+    /// Adds an operation to the undo log. This clears anything the player could have redone.
+    pub(super) fn record(&self, player: &PlayerId, op: Op, limit: usize) {
+        let mut history = self.history.lock().unwrap();
+        history.redo.retain(|e| &e.player != player);
+        History::push(
+            &mut history.undo,
+            Entry {
+                player: player.clone(),
+                op,
+            },
+            limit,
+        );
+    }
+
+    /// Reverses the player's most recent change, or anyone's if they're the host
+    pub(super) fn undo(
+        &self,
+        player: &Player,
+        config: &TableConfig,
+        icons: &IconIndex,
+    ) -> Result<Vec<TableUpdate>, Reason> {
+        self.replay(player, config, icons, true)
+    }
+
+    /// Reapplies the player's most recently undone change
+    pub(super) fn redo(
+        &self,
+        player: &Player,
+        config: &TableConfig,
+        icons: &IconIndex,
+    ) -> Result<Vec<TableUpdate>, Reason> {
+        self.replay(player, config, icons, false)
+    }
+
+    fn replay(
+        &self,
+        player: &Player,
+        config: &TableConfig,
+        icons: &IconIndex,
+        undo: bool,
+    ) -> Result<Vec<TableUpdate>, Reason> {
+        let any = self.is_host(player);
+        let mut history = self.history.lock().unwrap();
+        let History {
+            undo: undo_list,
+            redo: redo_list,
+        } = &mut *history;
+        let (from, to) = if undo {
+            (undo_list, redo_list)
+        } else {
+            (redo_list, undo_list)
+        };
+        let index = History::find(from, &player.id, any).ok_or(Reason::Invalid)?;
+        // Both lists hold operations the way they were first made, so undoing reverses them
+        let creates = match from[index].op {
+            Op::Delete(_) => undo,
+            Op::Create(_) => !undo,
+            Op::Move { .. } => false,
+        };
+        // A full table keeps the entry, so it can be reversed once there's room
+        if creates && self.is_full(config.element_limit) {
+            return Err(Reason::TooManyElements);
+        }
+        let entry = from.remove(index).ok_or(Reason::Invalid)?;
+        let op = if undo { entry.op.inverse() } else { entry.op };
+        // If the element has changed since, e.g. it was deleted, the entry is dropped
+        let (applied, updates) = self.apply(op, config, icons).ok_or(Reason::Invalid)?;
+        History::push(
+            to,
+            Entry {
+                player: entry.player,
+                op: if undo { applied.inverse() } else { applied },
+            },
+            config.history_limit,
+        );
+        Ok(updates)
+    }
+
+    /// Applies an operation, returning it with the element's current state along with the
+    /// updates to send to everyone at the table
+    fn apply(
+        &self,
+        op: Op,
+        config: &TableConfig,
+        icons: &IconIndex,
+    ) -> Option<(Op, Vec<TableUpdate>)> {
+        let guard = self.elements.guard();
+        match op {
+            Op::Create(el) => {
+                let icon = ItemState::Icon {
+                    icon_pack: el.icon_pack,
+                    icon_id: el.icon_id,
+                };
+                if !self.has_icon(&icon, icons) || !self.can_restore(&el) {
+                    return None;
+                }
+                let mut top = el.top.load(Ordering::Acquire);
+                let mut left = el.left.load(Ordering::Acquire);
+                config.size.clamp(&mut top, &mut left);
+                el.top.store(top, Ordering::Release);
+                el.left.store(left, Ordering::Release);
+                let id = el.element_id;
+                let updates = vec![
+                    TableUpdate::ElementCreate {
+                        icon_pack: el.icon_pack,
+                        icon_id: el.icon_id,
+                        id,
+                        top: el.top.load(Ordering::Acquire),
+                        left: el.left.load(Ordering::Acquire),
+                    },
+                    TableUpdate::PublicState {
+                        id,
+                        public_state: el.public_state(),
+                    },
+                ];
+                match self.elements.try_insert(id, el, &guard) {
+                    Ok(el) => Some((Op::Create(el.clone()), updates)),
+                    Err(_) => None,
+                }
+            }
+            Op::Delete(el) => {
+                let id = el.element_id;
+                let el = self.elements.remove(&id, &guard)?;
+                Some((
+                    Op::Delete(el.clone()),
+                    vec![TableUpdate::ElementDelete { id }],
+                ))
+            }
+            Op::Move { id, from, to } => {
+                let el = self.elements.get(&id, &guard)?;
+                el.top.store(to.0, Ordering::Release);
+                el.left.store(to.1, Ordering::Release);
+                Some((
+                    Op::Move { id, from, to },
+                    vec![TableUpdate::Position {
+                        id,
+                        top: to.0,
+                        left: to.1,
+                    }],
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> Player {
+        Player {
+            id: PlayerId::Guest("alice".into()),
+            name: "Alice".into(),
+        }
+    }
+
+    fn position(t: &TableState, id: u32) -> Option<(usize, usize)> {
+        let guard = t.elements.guard();
+        let el = t.elements.get(&id, &guard)?;
+        Some((el.top.load(Ordering::Acquire), el.left.load(Ordering::Acquire)))
+    }
+
+    fn element(t: &TableState, id: u32) -> ElementState {
+        t.elements.get(&id, &t.elements.guard()).unwrap().clone()
+    }
+
+    #[test]
+    fn moves_round_trip() {
+        let t = TableState::tester();
+        let (config, icons) = (TableConfig::default(), IconIndex::tester());
+        let alice = alice();
+        let moved = Op::Move {
+            id: 1,
+            from: (0, 0),
+            to: (5, 6),
+        };
+        let (moved, _) = t.apply(moved, &config, &icons).unwrap();
+        t.record(&alice.id, moved, 10);
+        t.undo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), Some((0, 0)));
+        t.redo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), Some((5, 6)));
+        t.undo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), Some((0, 0)));
+        assert_eq!(t.undo(&alice, &config, &icons).unwrap_err(), Reason::Invalid);
+    }
+
+    #[test]
+    fn creates_round_trip() {
+        let t = TableState::tester();
+        let (config, icons) = (TableConfig::default(), IconIndex::tester());
+        let alice = alice();
+        t.record(&alice.id, Op::Create(element(&t, 1)), 10);
+        t.undo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), None);
+        t.redo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), Some((0, 0)));
+        t.undo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), None);
+    }
+
+    #[test]
+    fn deletes_round_trip() {
+        let t = TableState::tester();
+        let (config, icons) = (TableConfig::default(), IconIndex::tester());
+        let alice = alice();
+        let el = element(&t, 1);
+        t.elements.remove(&1, &t.elements.guard());
+        t.record(&alice.id, Op::Delete(el), 10);
+        t.undo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), Some((0, 0)));
+        t.redo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), None);
+        t.undo(&alice, &config, &icons).unwrap();
+        assert_eq!(position(&t, 1), Some((0, 0)));
+    }
+}
@@ -0,0 +1,278 @@
+use rocket::{delete, get, post, serde::json::Json, State};
+use rocket::serde::Deserialize;
+
+use super::{
+    lookup_users, Denied, GlobalState, Player, PlayerId, SharingType, TableState, TableUpdate,
+};
+use crate::{account::DBConnInst, APIResponse, Empty, Error};
+
+// The same changes the host can make over the websocket. Each change is logged and queued as the
+// matching `TableUpdate`, so players at the table see it along with the next broadcast.
+
+/// Runs `f` on the table if the player is its host
+fn as_host<T>(
+    state: &GlobalState,
+    id: &str,
+    player: &Player,
+    f: impl FnOnce(&TableState) -> Result<T, &'static str>,
+) -> APIResponse<T> {
+    let guard = state.map.guard();
+    match state.map.get(id, &guard) {
+        Some(t) if t.is_host(player) => match f(t) {
+            Ok(ret) => {
+                t.touch();
+                t.mark_dirty();
+                APIResponse::ok(ret)
+            }
+            Err(text) => APIResponse::bad_request(Error {
+                text: text.to_string(),
+            }),
+        },
+        Some(_) => Denied::NotHost.response(),
+        None => APIResponse::not_found(Error {
+            text: format!("`{}` not found", id),
+        }),
+    }
+}
+
+/// Sends a change to everyone at the table, as if the host made it over the websocket
+fn notify(state: &GlobalState, t: &TableState, host: &Player, updates: Vec<TableUpdate>) {
+    if let Some((update, results)) = updates.split_first() {
+        t.log_update(host, update.clone(), results.to_vec());
+    }
+    t.queue(updates, state.config.resync_buffer);
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct KickPlayer {
+    player: PlayerId,
+}
+
+#[post("/api/table/<id>/kick", data = "<data>")]
+pub(super) fn kick(
+    id: &str,
+    data: Json<KickPlayer>,
+    player: Player,
+    state: &State<GlobalState>,
+) -> APIResponse<Empty> {
+    as_host(state, id, &player, |t| {
+        if t.kick(&data.player) {
+            let mut updates = vec![TableUpdate::Kick {
+                player: data.player.clone(),
+            }];
+            // Their sockets are closed the next time they send anything
+            if let Some(left) = t.disconnect_player(&data.player) {
+                updates.extend(t.departed(left));
+            }
+            notify(state, t, &player, updates);
+            Ok(Empty {})
+        } else {
+            Err("The host can't be kicked")
+        }
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct LockTable {
+    locked: bool,
+}
+
+#[post("/api/table/<id>/lock", data = "<data>")]
+pub(super) fn lock(
+    id: &str,
+    data: Json<LockTable>,
+    player: Player,
+    state: &State<GlobalState>,
+) -> APIResponse<Empty> {
+    as_host(state, id, &player, |t| {
+        t.set_locked(data.locked);
+        let update = TableUpdate::Lock {
+            locked: data.locked,
+        };
+        notify(state, t, &player, vec![update]);
+        Ok(Empty {})
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct ListTable {
+    listed: bool,
+}
+
+/// Shows or hides the table in the lobby. Only public tables are shown, whether they're listed or
+/// not.
+#[post("/api/table/<id>/listed", data = "<data>")]
+pub(super) fn listed(
+    id: &str,
+    data: Json<ListTable>,
+    player: Player,
+    state: &State<GlobalState>,
+) -> APIResponse<Empty> {
+    as_host(state, id, &player, |t| {
+        t.set_listed(data.listed);
+        let update = TableUpdate::Listed {
+            listed: data.listed,
+        };
+        notify(state, t, &player, vec![update]);
+        Ok(Empty {})
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct TransferHost<'a> {
+    username: &'a str,
+}
+
+#[post("/api/table/<id>/host", data = "<data>")]
+pub(super) async fn transfer_host(
+    id: &str,
+    data: Json<TransferHost<'_>>,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<Empty> {
+    let user = match find_users(&[data.username.to_string()], &mut db).await {
+        Ok(mut users) => PlayerId::User(users.remove(0).0),
+        Err(e) => return e,
+    };
+    as_host(state, id, &player, |t| {
+        if t.transfer_host(&user) {
+            notify(state, t, &player, vec![TableUpdate::TransferHost { player: user }]);
+            Ok(Empty {})
+        } else {
+            Err("Guests can't host a table")
+        }
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct RenameTable<'a> {
+    name: &'a str,
+}
+
+#[post("/api/table/<id>/rename", data = "<data>")]
+pub(super) fn rename(
+    id: &str,
+    data: Json<RenameTable<'_>>,
+    player: Player,
+    state: &State<GlobalState>,
+) -> APIResponse<Empty> {
+    as_host(state, id, &player, |t| {
+        if t.rename(data.name) {
+            notify(state, t, &player, vec![TableUpdate::Rename { name: t.name() }]);
+            Ok(Empty {})
+        } else {
+            Err("Table names can't be empty")
+        }
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct ChangeSharing {
+    sharing: SharingType,
+}
+
+#[post("/api/table/<id>/sharing", data = "<data>")]
+pub(super) async fn sharing(
+    id: &str,
+    data: Json<ChangeSharing>,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<Empty> {
+    let mut sharing = data.into_inner().sharing;
+    let invite = match find_users(&sharing.take_invites(), &mut db).await {
+        Ok(users) => users,
+        Err(e) => return e,
+    };
+    as_host(state, id, &player, move |t| {
+        t.whitelist_add(invite);
+        // Don't send the password to everyone
+        let update = TableUpdate::Sharing {
+            sharing: sharing.hidden(),
+        };
+        t.set_sharing(sharing);
+        notify(state, t, &player, vec![update]);
+        Ok(Empty {})
+    })
+}
+
+/// Looks up users by username, turning errors into responses
+async fn find_users<T>(
+    names: &[String],
+    db: &mut DBConnInst,
+) -> Result<Vec<(String, String)>, APIResponse<T>> {
+    match lookup_users(names, db).await {
+        Ok(users) => Ok(users),
+        Err(sqlx::Error::RowNotFound) => Err(APIResponse::not_found(Error {
+            text: format!("User not found"),
+        })),
+        Err(_e) => Err(APIResponse::internal_error(Error {
+            text: format!("Internal Error"),
+        })),
+    }
+}
+
+#[get("/api/table/<id>/whitelist")]
+pub(super) fn whitelist(
+    id: &str,
+    player: Player,
+    state: &State<GlobalState>,
+) -> APIResponse<Vec<String>> {
+    // Only looks at the table, so unlike `as_host` it doesn't count as activity
+    let guard = state.map.guard();
+    match state.map.get(id, &guard) {
+        Some(t) if t.is_host(&player) => APIResponse::ok(t.whitelist()),
+        Some(_) => Denied::NotHost.response(),
+        None => APIResponse::not_found(Error {
+            text: format!("`{}` not found", id),
+        }),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Invite<'a> {
+    username: &'a str,
+}
+
+/// Adds a user to the whitelist
+#[post("/api/table/<id>/whitelist", data = "<data>")]
+pub(super) async fn invite(
+    id: &str,
+    data: Json<Invite<'_>>,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<Vec<String>> {
+    let users = match find_users(&[data.username.to_string()], &mut db).await {
+        Ok(users) => users,
+        Err(e) => return e,
+    };
+    as_host(state, id, &player, move |t| {
+        t.whitelist_add(users);
+        Ok(t.whitelist())
+    })
+}
+
+#[delete("/api/table/<id>/whitelist/<username>")]
+pub(super) fn uninvite(
+    id: &str,
+    username: &str,
+    player: Player,
+    state: &State<GlobalState>,
+) -> APIResponse<Vec<String>> {
+    as_host(state, id, &player, |t| {
+        if t.whitelist_remove(username) {
+            Ok(t.whitelist())
+        } else {
+            Err("User is not on the whitelist")
+        }
+    })
+}
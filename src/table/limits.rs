@@ -0,0 +1,210 @@
+use std::time::{Duration, Instant};
+
+use rocket::serde::{Deserialize, Serialize};
+use rocket_db_pools::sqlx::MySqlPool;
+
+use super::{deck, ItemState, TableState, TableUpdate};
+
+/// Size of every table, in pixels
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct TableSize {
+    width: usize,
+    height: usize,
+}
+
+impl Default for TableSize {
+    fn default() -> Self {
+        Self {
+            width: 2000,
+            height: 2000,
+        }
+    }
+}
+
+impl TableSize {
+    /// Moves a position onto the table, if it's outside it
+    pub(super) fn clamp(&self, top: &mut usize, left: &mut usize) {
+        *top = (*top).min(self.height);
+        *left = (*left).min(self.width);
+    }
+}
+
+/// How long a pack is cached before it's loaded again, so icons added since are picked up
+const PACK_TTL: Duration = Duration::from_secs(60);
+
+/// The icons in each icon pack, so elements can't be created with icons that don't exist. Packs
+/// are loaded from the database the first time they're used, and again once they're older than
+/// `PACK_TTL`. Packs that don't exist aren't kept.
+#[derive(Debug, Default)]
+pub(super) struct IconIndex {
+    packs: flurry::HashMap<u32, PackIcons>,
+}
+
+#[derive(Debug)]
+struct PackIcons {
+    name: String,
+    icons: flurry::HashSet<u32>,
+    loaded: Instant,
+}
+
+impl IconIndex {
+    /// Loads any of the packs that haven't been loaded yet, or have expired
+    pub(super) async fn load(&self, packs: &[u32], db: &MySqlPool) {
+        for &pack in packs {
+            {
+                let guard = self.packs.guard();
+                match self.packs.get(&pack, &guard) {
+                    Some(cached) if cached.loaded.elapsed() < PACK_TTL => continue,
+                    _ => (),
+                }
+            }
+            // A pack with no icons still has a row, with a null icon
+            match sqlx::query!(
+                "SELECT p.name, i.icon_id FROM icon_packs p LEFT JOIN icons i ON i.table_id = p.id
+                WHERE p.id = ?",
+                pack
+            )
+            .fetch_all(db)
+            .await
+            {
+                Ok(rows) if rows.is_empty() => {
+                    // The pack was deleted
+                    self.packs.remove(&pack, &self.packs.guard());
+                }
+                Ok(rows) => {
+                    let name = rows[0].name.clone();
+                    let icons = flurry::HashSet::new();
+                    {
+                        let guard = icons.guard();
+                        for icon_id in rows.into_iter().filter_map(|row| row.icon_id) {
+                            icons.insert(icon_id, &guard);
+                        }
+                    }
+                    let loaded = PackIcons {
+                        name,
+                        icons,
+                        loaded: Instant::now(),
+                    };
+                    let guard = self.packs.guard();
+                    self.packs.insert(pack, loaded, &guard);
+                }
+                // Whatever was cached is kept, and loading is tried again next time
+                Err(e) => println!("Failed to load icons for pack {}: {:?}", pack, e),
+            }
+        }
+    }
+
+    /// Whether the pack exists, once it has been loaded
+    pub(super) fn has_pack(&self, icon_pack: u32) -> bool {
+        let guard = self.packs.guard();
+        self.packs.contains_key(&icon_pack, &guard)
+    }
+
+    /// The pack's name, once it has been loaded
+    pub(super) fn name(&self, icon_pack: u32) -> Option<String> {
+        let guard = self.packs.guard();
+        self.packs.get(&icon_pack, &guard).map(|p| p.name.clone())
+    }
+
+    /// An index with just icon 1 of pack 1, which `TableState::tester` uses
+    #[cfg(test)]
+    pub(super) fn tester() -> Self {
+        let index = Self::default();
+        let icons = flurry::HashSet::new();
+        icons.insert(1, &icons.guard());
+        let pack = PackIcons {
+            name: "Tester".into(),
+            icons,
+            loaded: Instant::now(),
+        };
+        index.packs.insert(1, pack, &index.packs.guard());
+        index
+    }
+
+    pub(super) fn contains(&self, icon_pack: u32, icon_id: u32) -> bool {
+        let guard = self.packs.guard();
+        match self.packs.get(&icon_pack, &guard) {
+            Some(pack) => pack.icons.contains(&icon_id, &pack.icons.guard()),
+            None => false,
+        }
+    }
+}
+
+impl TableUpdate {
+    /// The icon packs this update uses, which have to be loaded into the `IconIndex` first
+    pub(super) fn icon_packs(&self) -> Vec<u32> {
+        let items = match self {
+            Self::DeckCreate { cards, .. } => cards.as_slice(),
+            Self::CardCreate {
+                face: Some(face), ..
+            } => std::slice::from_ref(face),
+            Self::DiceCreate { faces, .. } => faces.as_slice(),
+            _ => &[],
+        };
+        let mut packs: Vec<u32> = items
+            .iter()
+            .filter_map(|item| match item {
+                ItemState::Icon { icon_pack, .. } => Some(*icon_pack),
+                _ => None,
+            })
+            .collect();
+        match self {
+            Self::ElementCreate { icon_pack, .. }
+            | Self::DeckCreate { icon_pack, .. }
+            | Self::CardCreate { icon_pack, .. }
+            | Self::DiceCreate { icon_pack, .. }
+            | Self::IconpackLoad { pack: icon_pack } => packs.push(*icon_pack),
+            _ => (),
+        }
+        packs.sort_unstable();
+        packs.dedup();
+        packs
+    }
+
+    /// Whether this creates a deck with more than `cards`, or a die with more than `faces`
+    pub(super) fn too_many_items(&self, cards: usize, faces: usize) -> bool {
+        match self {
+            Self::DeckCreate { cards: deck, .. } => deck.len() > cards,
+            Self::DiceCreate { faces: die, .. } => die.len() > faces,
+            _ => false,
+        }
+    }
+
+    /// Whether this update can add an element to the table
+    pub(super) fn adds_element(&self) -> bool {
+        match self {
+            Self::ElementCreate { .. }
+            | Self::DeckCreate { .. }
+            | Self::CardCreate { .. }
+            | Self::DiceCreate { .. }
+            | Self::FromHand { .. } => true,
+            Self::Action { act, .. } => act == deck::DRAW,
+            _ => false,
+        }
+    }
+}
+
+impl TableState {
+    /// Whether the table has as many elements as it's allowed
+    pub(super) fn is_full(&self, limit: usize) -> bool {
+        self.elements.len() >= limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_clamped() {
+        let size = TableSize {
+            width: 100,
+            height: 50,
+        };
+        let (mut top, mut left) = (80, 20);
+        size.clamp(&mut top, &mut left);
+        assert_eq!((top, left), (50, 20));
+    }
+}
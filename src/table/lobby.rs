@@ -0,0 +1,146 @@
+use chrono::Utc;
+use rocket::{get, serde::Serialize, State};
+use rocket_dyn_templates::Template;
+
+use super::{GlobalState, SharingType, TableState};
+use crate::{account::PUser, APIResponse, TemplateCtx};
+
+// Public tables can be listed in the lobby by their host, so anyone can find and join them.
+
+/// Tables shown on each page of the lobby
+const PAGE_SIZE: usize = 20;
+
+impl TableState {
+    pub(super) fn is_listed(&self) -> bool {
+        self.listed.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    pub(super) fn set_listed(&self, listed: bool) {
+        self.listed.store(listed, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Whether the table shows up in the lobby
+    fn in_lobby(&self) -> bool {
+        self.is_listed() && matches!(self.sharing(), SharingType::Public {})
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct LobbyTable {
+    id: String,
+    name: String,
+    created: chrono::DateTime<Utc>,
+    /// Names of the table's icon packs
+    icon_packs: Vec<String>,
+    /// Number of players connected to the table
+    players: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Lobby {
+    tables: Vec<LobbyTable>,
+    /// The search the tables matched, if any
+    q: Option<String>,
+    /// The current page, starting from 1
+    page: usize,
+    pages: usize,
+}
+
+/// Listed tables with names containing `q`, busiest first
+async fn lobby(state: &GlobalState, q: Option<&str>, page: Option<usize>) -> Lobby {
+    let q = q.map(str::trim).filter(|q| !q.is_empty());
+    let search = q.map(str::to_lowercase);
+    // Each table along with the ids of its icon packs, which are named once the page is known
+    let mut tables: Vec<_> = {
+        let guard = state.map.guard();
+        state
+            .map
+            .iter(&guard)
+            .filter(|(_, t)| t.in_lobby())
+            .filter(|(_, t)| {
+                search
+                    .as_ref()
+                    .map_or(true, |q| t.name().to_lowercase().contains(q))
+            })
+            .map(|(id, t)| {
+                let table = LobbyTable {
+                    id: id.clone(),
+                    name: t.name(),
+                    created: t.created,
+                    icon_packs: vec![],
+                    players: t.players().len(),
+                };
+                let guard = t.icon_packs.guard();
+                (table, t.icon_packs.iter(&guard).copied().collect::<Vec<_>>())
+            })
+            .collect()
+    };
+    tables.sort_by(|(a, _), (b, _)| {
+        b.players.cmp(&a.players).then_with(|| b.created.cmp(&a.created))
+    });
+    let pages = ((tables.len() + PAGE_SIZE - 1) / PAGE_SIZE).max(1);
+    let page = page.unwrap_or(1).clamp(1, pages);
+    let tables: Vec<_> = tables
+        .into_iter()
+        .skip((page - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .collect();
+    // Pack names are cached, so only packs no table has used yet are looked up
+    let mut packs: Vec<u32> = tables.iter().flat_map(|(_, packs)| packs.clone()).collect();
+    packs.sort_unstable();
+    packs.dedup();
+    state.icons.load(&packs, &state.db).await;
+    Lobby {
+        tables: tables
+            .into_iter()
+            .map(|(mut table, packs)| {
+                table.icon_packs = packs.into_iter().filter_map(|p| state.icons.name(p)).collect();
+                table
+            })
+            .collect(),
+        q: q.map(String::from),
+        page,
+        pages,
+    }
+}
+
+#[get("/api/lobby?<q>&<page>")]
+pub(super) async fn lobby_tables(
+    q: Option<&str>,
+    page: Option<usize>,
+    state: &State<GlobalState>,
+) -> APIResponse<Lobby> {
+    APIResponse::ok(lobby(state, q, page).await)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LobbyCtx {
+    #[serde(flatten)]
+    tem: TemplateCtx,
+    lobby: Lobby,
+}
+
+#[get("/lobby?<q>&<page>")]
+pub(super) async fn lobby_page(
+    q: Option<&str>,
+    page: Option<usize>,
+    user: PUser<'_>,
+    state: &State<GlobalState>,
+) -> Template {
+    let lobby = lobby(state, q, page).await;
+    Template::render(
+        "lobby",
+        LobbyCtx {
+            tem: TemplateCtx {
+                page: "lobby",
+                error: None,
+                user: user.map(|u| u.info().clone()),
+                update_url: None,
+            },
+            lobby,
+        },
+    )
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use rocket::{
+    delete, futures::StreamExt, get, post, response::Redirect, serde::Serialize, uri, State,
+};
+use rocket_dyn_templates::Template;
+
+use super::{add_table, persist, Denied, GlobalState, Player, PlayerId, TableName, TableState};
+use crate::{
+    account::{DBConnInst, PUser},
+    APIResponse, Empty, Error, TemplateCtx,
+};
+
+// Every time a player opens a table it's recorded in `table_visits`, so users can find tables
+// they've played on again. The same table is used to count how many players a table has had.
+//
+// Players still connected to a table when it's deleted are told the next time any of them sends
+// something, for a while after it's deleted.
+
+/// Number of recently joined tables shown
+const RECENT_LIMIT: usize = 20;
+/// Seconds a deleted table is remembered for
+const DELETED_TTL: i64 = 60 * 60;
+
+/// How a player is stored in `table_visits`
+fn visitor(player: &PlayerId) -> String {
+    match player {
+        PlayerId::User(id) => format!("user:{}", id),
+        PlayerId::Guest(id) => format!("guest:{}", id),
+    }
+}
+
+/// Records that the player opened a table
+pub(super) async fn record_visit(
+    db: &mut DBConnInst,
+    id: &str,
+    player: &PlayerId,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO table_visits (table_id, player, visited) VALUES (?, ?, NOW())
+        ON DUPLICATE KEY UPDATE visited = NOW()",
+        id,
+        visitor(player),
+    )
+    .execute(db.con())
+    .await?;
+    Ok(())
+}
+
+pub(super) async fn delete_visits<'e>(
+    db: impl sqlx::MySqlExecutor<'e>,
+    id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM table_visits WHERE table_id = ?", id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct TableSummary {
+    id: String,
+    name: String,
+    /// Whether the user is the table's host
+    hosted: bool,
+    /// Unix timestamp of the last time anyone used the table
+    last_active: i64,
+    /// Number of players that have opened the table
+    players: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct MyTables {
+    hosted: Vec<TableSummary>,
+    /// Tables the user has recently opened, but doesn't host
+    recent: Vec<TableSummary>,
+}
+
+/// The tables a user hosts or has recently joined
+async fn my_tables(
+    state: &GlobalState,
+    user: &str,
+    db: &mut DBConnInst,
+) -> Result<MyTables, sqlx::Error> {
+    let me = visitor(&PlayerId::User(user.to_string()));
+    let players: HashMap<String, i64> = sqlx::query!(
+        "SELECT v.table_id, COUNT(*) AS players FROM table_visits v
+        JOIN table_visits mine ON mine.table_id = v.table_id
+        WHERE mine.player = ? GROUP BY v.table_id",
+        me
+    )
+    .fetch_all(db.con())
+    .await?
+    .into_iter()
+    .map(|row| (row.table_id, row.players))
+    .collect();
+    let player = Player {
+        id: PlayerId::User(user.to_string()),
+        name: String::new(),
+    };
+    let summary = |id: &str, t: &TableState| TableSummary {
+        id: id.to_string(),
+        name: t.name(),
+        hosted: t.is_host(&player),
+        last_active: t.last_active(),
+        players: players.get(id).copied().unwrap_or(0),
+    };
+    let mut hosted: Vec<_> = {
+        let guard = state.map.guard();
+        state
+            .map
+            .iter(&guard)
+            .filter(|(_, t)| t.is_host(&player))
+            .map(|(id, t)| summary(id, t))
+            .collect()
+    };
+    hosted.sort_by_key(|t| -t.last_active);
+    // Visits are read until there are enough tables, since tables that have expired since they
+    // were visited, or that the user hosts, are left out
+    let mut recent = vec![];
+    let mut visits = sqlx::query!(
+        "SELECT table_id FROM table_visits WHERE player = ? ORDER BY visited DESC",
+        me
+    )
+    .fetch(db.con());
+    while let Some(row) = visits.next().await {
+        let id = row?.table_id;
+        let table = {
+            let guard = state.map.guard();
+            state.map.get(&id, &guard).map(|t| summary(&id, t))
+        };
+        match table {
+            Some(table) if !table.hosted => recent.push(table),
+            _ => continue,
+        }
+        if recent.len() >= RECENT_LIMIT {
+            break;
+        }
+    }
+    Ok(MyTables { hosted, recent })
+}
+
+#[get("/api/account/tables")]
+pub(super) async fn tables(
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<MyTables> {
+    let user = match &player.id {
+        PlayerId::User(user) => user,
+        PlayerId::Guest(_) => {
+            return APIResponse::unauthorized(Error {
+                text: format!("You must be logged in to see your tables"),
+            })
+        }
+    };
+    match my_tables(state, user, &mut db).await {
+        Ok(tables) => APIResponse::ok(tables),
+        Err(_e) => APIResponse::internal_error(Error {
+            text: format!("Internal Error"),
+        }),
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TablesCtx {
+    #[serde(flatten)]
+    tem: TemplateCtx,
+    #[serde(flatten)]
+    tables: MyTables,
+}
+
+#[get("/account/tables")]
+pub(super) async fn tables_page(
+    user: PUser<'_>,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> Result<Template, Redirect> {
+    let (user, id) = match (user, &player.id) {
+        (Some(user), PlayerId::User(id)) => (user, id),
+        _ => return Err(Redirect::to(uri!("/account/login"))),
+    };
+    let (tables, error) = match my_tables(state, id, &mut db).await {
+        Ok(tables) => (tables, None),
+        Err(e) => {
+            println!("Failed to list tables for {}: {:?}", id, e);
+            (
+                MyTables {
+                    hosted: vec![],
+                    recent: vec![],
+                },
+                Some("Failed to load your tables"),
+            )
+        }
+    };
+    Ok(Template::render(
+        "account/tables",
+        TablesCtx {
+            tem: TemplateCtx {
+                page: "account/tables",
+                error,
+                user: Some(user.info().clone()),
+                update_url: None,
+            },
+            tables,
+        },
+    ))
+}
+
+/// Creates a copy of a table, hosted by the same player
+#[post("/api/table/<id>/duplicate")]
+pub(super) async fn duplicate(
+    id: &str,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<TableName> {
+    let mut saved = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) if t.is_host(&player) => persist::SavedTable::from(t),
+            Some(_) => return Denied::NotHost.response(),
+            None => {
+                return APIResponse::not_found(Error {
+                    text: format!("`{}` not found", id),
+                })
+            }
+        }
+    };
+    let host = match &player.id {
+        PlayerId::User(user) => Some(user.clone()),
+        PlayerId::Guest(_) => None,
+    };
+    saved.reset(host);
+    let table = TableState::from(saved);
+    table.rename(&format!("{} (copy)", table.name()));
+    table.log_snapshot(Some(&player));
+    add_table(state, table, &mut db).await
+}
+
+/// Whether the host deleted the table recently
+pub(super) fn was_deleted(state: &GlobalState, id: &str) -> bool {
+    let guard = state.deleted.guard();
+    state.deleted.contains_key(id, &guard)
+}
+
+/// Deletes a table, along with its log
+#[delete("/api/table/<id>")]
+pub(super) async fn delete_table(
+    id: &str,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<Empty> {
+    // Waits for any snapshot to finish, which would otherwise save the table again
+    let _saving = state.saving.lock().await;
+    {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) if t.is_host(&player) => {
+                state.map.remove(id, &guard);
+                let now = Utc::now().timestamp();
+                let deleted_guard = state.deleted.guard();
+                state
+                    .deleted
+                    .retain(|_id, at| now - *at < DELETED_TTL, &deleted_guard);
+                state.deleted.insert(id.to_string(), now, &deleted_guard);
+            }
+            Some(_) => return Denied::NotHost.response(),
+            None => {
+                return APIResponse::not_found(Error {
+                    text: format!("`{}` not found", id),
+                })
+            }
+        }
+    }
+    let res = match persist::delete_table(db.con(), id).await {
+        Ok(()) => persist::delete_log(db.con(), id).await,
+        res => res,
+    };
+    let res = match res {
+        Ok(()) => delete_visits(db.con(), id).await,
+        res => res,
+    };
+    match res {
+        Ok(()) => {
+            println!("Table `{}` deleted by its host", id);
+            APIResponse::ok(Empty {})
+        }
+        Err(e) => {
+            println!("Failed to delete table `{}`: {:?}", id, e);
+            APIResponse::internal_error(Error {
+                text: format!("Internal Error"),
+            })
+        }
+    }
+}
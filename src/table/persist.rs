@@ -0,0 +1,481 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+use chrono::Utc;
+use rocket::serde::{json::serde_json, Deserialize, Serialize};
+use rocket_auth::UserId;
+use rocket_db_pools::sqlx::{self, MySqlPool};
+
+use super::{
+    chat::Chat, deck, hand::Hand, replay::LogEntry, Action, ElementState, PlayerId, Property,
+    SharingType, TableConfig, TableState,
+};
+
+/// The complete state of a table, including everything that is hidden from clients.
+///
+/// This is what gets written to the `tables` table, as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct SavedTable {
+    created: chrono::DateTime<Utc>,
+    name: String,
+    sharing: SharingType,
+    host: Option<String>,
+    #[serde(default)]
+    locked: bool,
+    /// Whether the table is shown in the lobby
+    #[serde(default)]
+    listed: bool,
+    #[serde(default)]
+    kicked: Vec<PlayerId>,
+    /// Pairs of `UserId`s and usernames
+    #[serde(default)]
+    whitelist: Vec<(String, String)>,
+    cur_id: u32,
+    elements: Vec<SavedElement>,
+    icon_packs: Vec<u32>,
+    #[serde(default)]
+    hands: Vec<Hand>,
+    #[serde(default)]
+    chat: Chat,
+    /// Sequence number of the next log entry
+    #[serde(default)]
+    log_seq: u64,
+    /// Unix timestamp, zero if unknown
+    #[serde(default)]
+    last_active: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SavedElement {
+    id: u32,
+    icon_pack: u32,
+    icon_id: u32,
+    top: usize,
+    left: usize,
+    public_state: BTreeMap<String, Property>,
+    private_state: BTreeMap<String, Property>,
+    action: BTreeMap<String, Action>,
+}
+
+impl SavedElement {
+    /// Whether this is a deck with more than `cards`, or a die with more than `faces`
+    fn too_many_items(&self, cards: usize, faces: usize) -> bool {
+        let too_many = |key: &str, limit| match self.private_state.get(key) {
+            Some(Property::Stack(items)) => items.len() > limit,
+            _ => false,
+        };
+        self.action.values().any(|action| match action {
+            // Resetting a deck puts every card it started with back
+            Action::Draw(key) => too_many(key, cards) || too_many(deck::ALL, cards),
+            Action::Select(key) => too_many(key, faces),
+        })
+    }
+}
+
+fn save_map<V: Clone>(map: &flurry::HashMap<String, V>) -> BTreeMap<String, V> {
+    let guard = map.guard();
+    map.iter(&guard)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+impl From<&ElementState> for SavedElement {
+    fn from(el: &ElementState) -> Self {
+        Self {
+            id: el.element_id,
+            icon_pack: el.icon_pack,
+            icon_id: el.icon_id,
+            top: el.top.load(Ordering::Acquire),
+            left: el.left.load(Ordering::Acquire),
+            public_state: save_map(&el.public_state),
+            private_state: save_map(&el.private_state),
+            action: save_map(&el.action),
+        }
+    }
+}
+
+impl From<SavedElement> for ElementState {
+    fn from(el: SavedElement) -> Self {
+        Self {
+            icon_pack: el.icon_pack,
+            icon_id: el.icon_id,
+            element_id: el.id,
+            top: AtomicUsize::new(el.top),
+            left: AtomicUsize::new(el.left),
+            public_state: el.public_state.into_iter().collect(),
+            private_state: el.private_state.into_iter().collect(),
+            action: el.action.into_iter().collect(),
+        }
+    }
+}
+
+impl From<&TableState> for SavedTable {
+    fn from(table: &TableState) -> Self {
+        let elements = {
+            let guard = table.elements.guard();
+            table
+                .elements
+                .values(&guard)
+                .map(SavedElement::from)
+                .collect()
+        };
+        let icon_packs = {
+            let guard = table.icon_packs.guard();
+            table.icon_packs.iter(&guard).copied().collect()
+        };
+        let kicked = {
+            let guard = table.kicked.guard();
+            table.kicked.iter(&guard).cloned().collect()
+        };
+        let hands = {
+            let guard = table.hands.guard();
+            table.hands.values(&guard).cloned().collect()
+        };
+        let whitelist = {
+            let guard = table.whitelist.guard();
+            table
+                .whitelist
+                .iter(&guard)
+                .map(|(id, name)| (id.clone(), name.clone()))
+                .collect()
+        };
+        Self {
+            created: table.created,
+            name: table.name(),
+            sharing: table.sharing(),
+            host: table.host().map(|u| u.0.to_string()),
+            locked: table.is_locked(),
+            listed: table.is_listed(),
+            kicked,
+            whitelist,
+            cur_id: table.cur_id.load(Ordering::Acquire),
+            elements,
+            icon_packs,
+            hands,
+            chat: table.chat.lock().unwrap().clone(),
+            log_seq: table.log_seq.load(Ordering::Acquire),
+            last_active: table.last_active(),
+        }
+    }
+}
+
+impl From<SavedTable> for TableState {
+    fn from(table: SavedTable) -> Self {
+        Self {
+            created: table.created,
+            name: RwLock::new(table.name),
+            sharing: RwLock::new(table.sharing),
+            host: RwLock::new(table.host.map(|u| UserId(u.into()))),
+            locked: AtomicBool::new(table.locked),
+            listed: AtomicBool::new(table.listed),
+            kicked: table.kicked.into_iter().collect(),
+            whitelist: table.whitelist.into_iter().collect(),
+            cur_id: AtomicU32::new(table.cur_id),
+            elements: table
+                .elements
+                .into_iter()
+                .map(|el| (el.id, ElementState::from(el)))
+                .collect(),
+            icon_packs: table.icon_packs.into_iter().collect(),
+            hands: table
+                .hands
+                .into_iter()
+                .map(|hand| (hand.owner().clone(), hand))
+                .collect(),
+            history: Mutex::default(),
+            log: Mutex::default(),
+            log_seq: AtomicU64::new(table.log_seq),
+            sequencer: Mutex::default(),
+            drags: Mutex::default(),
+            grabs: Mutex::default(),
+            chat: Mutex::new(table.chat),
+            cursors: Mutex::default(),
+            present: Mutex::default(),
+            dirty: AtomicBool::new(false),
+            last_active: AtomicI64::new(if table.last_active == 0 {
+                Utc::now().timestamp()
+            } else {
+                table.last_active
+            }),
+        }
+    }
+}
+
+impl SavedTable {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Table state is always valid json")
+    }
+
+    pub(super) fn sharing(&self) -> &SharingType {
+        &self.sharing
+    }
+
+    /// Blanks the password, so it isn't given to anyone who gets hold of the table
+    pub(super) fn hide_password(&mut self) {
+        self.sharing = self.sharing.hidden();
+    }
+
+    pub(super) fn set_sharing(&mut self, sharing: SharingType) {
+        self.sharing = sharing;
+    }
+
+    pub(super) fn icon_packs(&self) -> &[u32] {
+        &self.icon_packs
+    }
+
+    /// Every icon used on the table, including hidden ones, as `(icon_pack, icon_id)`
+    pub(super) fn icons(&self) -> Vec<(u32, u32)> {
+        let mut icons = vec![];
+        for el in &self.elements {
+            icons.push((el.icon_pack, el.icon_id));
+            for prop in el.public_state.values().chain(el.private_state.values()) {
+                icons.extend(prop.icons());
+            }
+        }
+        for hand in &self.hands {
+            icons.extend(hand.icons());
+        }
+        icons
+    }
+
+    /// Makes a table from another server fit on this one. It has to be within this server's
+    /// limits, and elements are moved onto the table. Users are forgotten since their ids are
+    /// different here, along with their hands and messages.
+    pub(super) fn sanitize(&mut self, config: &TableConfig) -> Result<(), &'static str> {
+        if self.elements.len() > config.element_limit {
+            return Err("The table has too many elements");
+        }
+        if self.icon_packs.len() > config.pack_limit {
+            return Err("The table uses too many icon packs");
+        }
+        if self
+            .elements
+            .iter()
+            .any(|el| el.too_many_items(config.deck_size, config.dice_faces))
+        {
+            return Err("The table has a deck or die that's too big");
+        }
+        for el in &mut self.elements {
+            config.size.clamp(&mut el.top, &mut el.left);
+        }
+        // New elements can't reuse an id
+        if let Some(max) = self.elements.iter().map(|el| el.id).max() {
+            let next = max.checked_add(1).ok_or("The table has an invalid element id")?;
+            self.cur_id = self.cur_id.max(next);
+        }
+        self.whitelist.clear();
+        self.kicked.clear();
+        self.hands.clear();
+        self.chat = Chat::default();
+        Ok(())
+    }
+
+    /// Changes the ids of icon packs, e.g. when a table is imported from another server
+    pub(super) fn remap_icon_packs(&mut self, ids: &HashMap<u32, u32>) {
+        for pack in &mut self.icon_packs {
+            *pack = ids.get(pack).copied().unwrap_or(*pack);
+        }
+        for el in &mut self.elements {
+            el.icon_pack = ids.get(&el.icon_pack).copied().unwrap_or(el.icon_pack);
+            for prop in el
+                .public_state
+                .values_mut()
+                .chain(el.private_state.values_mut())
+            {
+                prop.remap_icon_packs(ids);
+            }
+        }
+        for hand in &mut self.hands {
+            hand.remap_icon_packs(ids);
+        }
+    }
+
+    /// Strips everything except the layout of the table, i.e. its name, elements and icon
+    /// packs, for use as a template
+    pub(super) fn layout(mut self) -> Self {
+        self.sharing = SharingType::Public {};
+        self.host = None;
+        self.locked = false;
+        self.listed = false;
+        self.kicked.clear();
+        self.whitelist.clear();
+        self.hands.clear();
+        self.chat = Chat::default();
+        self
+    }
+
+    /// Turns this into a brand new table, hosted by `host`
+    pub(super) fn reset(&mut self, host: Option<String>) {
+        let now = Utc::now();
+        self.created = now;
+        self.last_active = now.timestamp();
+        self.host = host;
+        self.kicked.clear();
+        self.chat = Chat::default();
+        self.log_seq = 0;
+    }
+}
+
+/// Loads every saved table from the database
+pub(super) async fn load_tables(db: &MySqlPool) -> Result<Vec<(String, TableState)>, sqlx::Error> {
+    let rows = sqlx::query!("SELECT id, state FROM tables WHERE NOT archived")
+        .fetch_all(db)
+        .await?;
+    let mut ret = Vec::with_capacity(rows.len());
+    for row in rows {
+        match serde_json::from_str::<SavedTable>(&row.state) {
+            Ok(saved) => ret.push((row.id, saved.into())),
+            Err(e) => println!("Failed to load table `{}`: {}", row.id, e),
+        }
+    }
+    Ok(ret)
+}
+
+/// Writes (or overwrites) a single table
+pub(super) async fn save_table<'e>(
+    db: impl sqlx::MySqlExecutor<'e>,
+    id: &str,
+    state: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO tables (id, state, updated, archived) VALUES (?, ?, NOW(), FALSE)
+        ON DUPLICATE KEY UPDATE state = VALUES(state), updated = NOW(), archived = FALSE",
+        id,
+        state,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub(super) async fn table_exists<'e>(
+    db: impl sqlx::MySqlExecutor<'e>,
+    id: &str,
+) -> Result<bool, sqlx::Error> {
+    Ok(sqlx::query!("SELECT id FROM tables WHERE id = ?", id)
+        .fetch_optional(db)
+        .await?
+        .is_some())
+}
+
+/// Saves the final state of a table, and marks it so it won't be loaded again
+pub(super) async fn archive_table<'e>(
+    db: impl sqlx::MySqlExecutor<'e>,
+    id: &str,
+    state: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO tables (id, state, updated, archived) VALUES (?, ?, NOW(), TRUE)
+        ON DUPLICATE KEY UPDATE state = VALUES(state), updated = NOW(), archived = TRUE",
+        id,
+        state,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub(super) async fn delete_table<'e>(
+    db: impl sqlx::MySqlExecutor<'e>,
+    id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM tables WHERE id = ?", id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Appends entries to a table's log
+pub(super) async fn save_log(
+    db: &MySqlPool,
+    id: &str,
+    entries: &[LogEntry],
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    // Entries are written together, so ones that are retried after an error aren't doubled
+    for entry in entries {
+        sqlx::query!(
+            "INSERT INTO table_log (table_id, seq, at, entry, snapshot)
+            VALUES (?, ?, ?, ?, ?)",
+            id,
+            entry.seq(),
+            entry.at(),
+            entry.to_json(),
+            entry.is_snapshot(),
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Every saved log entry for a table, as JSON, in order
+pub(super) async fn load_log<'e>(
+    db: impl sqlx::MySqlExecutor<'e>,
+    id: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    Ok(
+        sqlx::query!("SELECT entry FROM table_log WHERE table_id = ? ORDER BY seq", id)
+            .fetch_all(db)
+            .await?
+            .into_iter()
+            .map(|row| row.entry)
+            .collect(),
+    )
+}
+
+/// Deletes log entries from before `before`, a unix timestamp in milliseconds. Each table keeps
+/// everything from its latest snapshot before then.
+pub(super) async fn prune_log(db: &MySqlPool, before: i64) -> Result<u64, sqlx::Error> {
+    // The grouped subquery is materialized, which lets MySQL delete from the table it reads
+    Ok(sqlx::query!(
+        "DELETE l FROM table_log l JOIN (
+            SELECT table_id, MAX(seq) AS seq FROM table_log
+            WHERE snapshot AND at < ? GROUP BY table_id
+        ) s ON l.table_id = s.table_id AND l.seq < s.seq",
+        before,
+    )
+    .execute(db)
+    .await?
+    .rows_affected())
+}
+
+pub(super) async fn delete_log<'e>(
+    db: impl sqlx::MySqlExecutor<'e>,
+    id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM table_log WHERE table_id = ?", id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let table = TableState::tester();
+        let json = SavedTable::from(&table).to_json();
+        let loaded: TableState = serde_json::from_str::<SavedTable>(&json)
+            .expect("Failed to parse saved table")
+            .into();
+        assert_eq!(loaded.name(), table.name());
+        assert_eq!(loaded.sharing(), table.sharing());
+        assert_eq!(
+            loaded.cur_id.load(Ordering::Acquire),
+            table.cur_id.load(Ordering::Acquire)
+        );
+        assert_eq!(loaded.elements, table.elements);
+        assert_eq!(loaded.icon_packs, table.icon_packs);
+    }
+}
@@ -0,0 +1,107 @@
+use std::convert::Infallible;
+
+use rand::Rng;
+use rocket::{
+    http::{Cookie, CookieJar},
+    post,
+    request::{FromRequest, Outcome},
+    serde::{json::Json, Deserialize, Serialize},
+    Request,
+};
+
+use crate::{account::PUser, APIResponse, Empty, Error};
+
+/// Name of the private cookie that identifies guests
+const GUEST_COOKIE: &str = "guest";
+/// Name of the private cookie with the name a guest picked
+const NICKNAME_COOKIE: &str = "nickname";
+/// Longest nickname a guest can pick, in characters
+const NICKNAME_LENGTH: usize = 32;
+
+/// Uniquely identifies someone at a table
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "t", content = "id", rename_all = "snake_case")]
+pub enum PlayerId {
+    /// A logged in user, with their `UserId`
+    User(String),
+    /// A guest, identified by a random id stored in a private cookie
+    Guest(String),
+}
+
+/// Someone using a table, either a logged in user or a guest.
+///
+/// Guests are given an id the first time they use this guard, so it should be used when loading
+/// the table page to make sure the websocket gets the same id. They're named after their id until
+/// they pick a nickname.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Player {
+    pub id: PlayerId,
+    pub name: String,
+}
+
+impl Player {
+    pub fn is_guest(&self) -> bool {
+        matches!(self.id, PlayerId::Guest(_))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Player {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(user) = req.guard::<PUser<'r>>().await.succeeded().flatten() {
+            return Outcome::Success(Self {
+                id: PlayerId::User(user.id().0.to_string()),
+                name: user.info().username().to_string(),
+            });
+        }
+        let cookies = req.cookies();
+        let id = match cookies.get_private(GUEST_COOKIE) {
+            Some(c) => c.value().to_string(),
+            None => {
+                let id = format!("{:016X}", rand::thread_rng().gen::<u64>());
+                cookies.add_private(Cookie::new(GUEST_COOKIE, id.clone()));
+                id
+            }
+        };
+        let name = match cookies.get_private(NICKNAME_COOKIE) {
+            Some(c) => c.value().to_string(),
+            None => format!("Guest {}", &id[..4]),
+        };
+        Outcome::Success(Self {
+            name,
+            id: PlayerId::Guest(id),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Nickname<'a> {
+    nickname: &'a str,
+}
+
+/// Sets the name a guest is shown with. Users are always shown with their username.
+#[post("/api/nickname", data = "<data>")]
+pub(super) fn set_nickname(
+    data: Json<Nickname<'_>>,
+    player: Player,
+    cookies: &CookieJar<'_>,
+) -> APIResponse<Empty> {
+    if !player.is_guest() {
+        return APIResponse::bad_request(Error {
+            text: format!("Logged in users are shown with their username"),
+        });
+    }
+    let nickname = data.nickname.trim();
+    if nickname.is_empty() || nickname.chars().count() > NICKNAME_LENGTH {
+        return APIResponse::bad_request(Error {
+            text: format!("Nicknames have to be 1 to {} characters", NICKNAME_LENGTH),
+        });
+    }
+    cookies.add_private(Cookie::new(NICKNAME_COOKIE, nickname.to_string()));
+    APIResponse::ok(Empty {})
+}
@@ -0,0 +1,63 @@
+use std::time::Instant;
+
+use super::{
+    coalesce::{self, Offer},
+    PlayerId, TableState, TableUpdate,
+};
+
+// Players can show each other where they're pointing, either by sharing their cursor or by
+// pinging a spot on the table. Like drag previews, these are only broadcast, they're never saved,
+// logged or versioned. Cursors are coalesced like drag previews, so at most one per player is
+// broadcast each tick, and wherever the cursor stopped is always sent, see `coalesce`.
+
+impl TableState {
+    /// Decides when to broadcast the player's cursor. `rate` is the number of ticks per second.
+    pub(super) fn cursor_tick(&self, player: &PlayerId, update: TableUpdate, rate: u32) -> Offer {
+        self.cursor_tick_at(player, update, rate, Instant::now())
+    }
+
+    fn cursor_tick_at(
+        &self,
+        player: &PlayerId,
+        update: TableUpdate,
+        rate: u32,
+        now: Instant,
+    ) -> Offer {
+        let mut cursors = self.cursors.lock().unwrap();
+        coalesce::offer(&mut cursors, player.clone(), update, rate, now)
+    }
+
+    /// Forgets the player's cursor once they've left, dropping anything waiting to be sent
+    pub(super) fn end_cursor(&self, player: &PlayerId) {
+        self.cursors.lock().unwrap().remove(player);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::table::Player;
+
+    #[test]
+    fn one_cursor_per_tick() {
+        let t = TableState::tester();
+        let (alice, bob) = (PlayerId::Guest("alice".into()), PlayerId::Guest("bob".into()));
+        let moved = |id: &PlayerId| TableUpdate::CursorMoved {
+            player: Player {
+                id: id.clone(),
+                name: "Someone".into(),
+            },
+            top: 0,
+            left: 0,
+        };
+        let now = Instant::now();
+        assert_eq!(t.cursor_tick_at(&alice, moved(&alice), 10, now), Offer::Now);
+        let later = now + Duration::from_millis(50);
+        assert_ne!(t.cursor_tick_at(&alice, moved(&alice), 10, later), Offer::Now);
+        assert_eq!(t.cursor_tick_at(&bob, moved(&bob), 10, later), Offer::Now);
+        t.end_cursor(&alice);
+        assert_eq!(t.cursor_tick_at(&alice, moved(&alice), 10, later), Offer::Now);
+    }
+}
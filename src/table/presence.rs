@@ -0,0 +1,100 @@
+use std::net::SocketAddr;
+
+use super::{Player, PlayerId, TableState, TableUpdate};
+
+// Each websocket connected to a table is tracked, so everyone can see who's at the table. A player
+// can be connected more than once, e.g. with the table open in two tabs, so they only join when
+// their first connection opens and leave when their last one closes. Joins and leaves are
+// versioned, so clients that miss one catch up when they resync.
+
+impl TableState {
+    /// Records a new connection. Returns whether it's the player's first one.
+    pub(super) fn connect(&self, addr: SocketAddr, player: &Player) -> bool {
+        let mut present = self.present.lock().unwrap();
+        let joined = !present.values().any(|p| p.id == player.id);
+        present.insert(addr, player.clone());
+        joined
+    }
+
+    /// Forgets a connection. Returns the player if it was their last one.
+    pub(super) fn disconnect(&self, addr: SocketAddr) -> Option<Player> {
+        let mut present = self.present.lock().unwrap();
+        let player = present.remove(&addr)?;
+        if present.values().any(|p| p.id == player.id) {
+            None
+        } else {
+            Some(player)
+        }
+    }
+
+    /// Forgets all of a player's connections, e.g. when they're kicked. Returns the player if they
+    /// were connected.
+    pub(super) fn disconnect_player(&self, id: &PlayerId) -> Option<Player> {
+        let mut left = None;
+        self.present.lock().unwrap().retain(|_addr, p| {
+            if p.id == *id {
+                left = Some(p.clone());
+                false
+            } else {
+                true
+            }
+        });
+        left
+    }
+
+    /// Forgets a connection, returning the updates to broadcast if it was the player's last one
+    pub(super) fn leave(&self, addr: SocketAddr) -> Vec<TableUpdate> {
+        match self.disconnect(addr) {
+            Some(player) => self.departed(player),
+            None => vec![],
+        }
+    }
+
+    /// Cleans up after a player who isn't connected anymore, returning the updates to broadcast.
+    /// Their cursor goes away, and everything they were holding is let go.
+    pub(super) fn departed(&self, player: Player) -> Vec<TableUpdate> {
+        self.end_cursor(&player.id);
+        let mut updates: Vec<_> = self
+            .release_all(&player.id)
+            .into_iter()
+            .map(|id| TableUpdate::Release { id })
+            .collect();
+        updates.push(TableUpdate::Left { player });
+        updates
+    }
+
+    /// Everyone connected to the table, by name
+    pub(super) fn players(&self) -> Vec<Player> {
+        let mut players: Vec<_> = self.present.lock().unwrap().values().cloned().collect();
+        players.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        players.dedup_by(|a, b| a.id == b.id);
+        players
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn players_join_once() {
+        let t = TableState::tester();
+        let alice = Player {
+            id: PlayerId::Guest("alice".into()),
+            name: "Alice".into(),
+        };
+        let (tab, other_tab): (SocketAddr, SocketAddr) =
+            ("127.0.0.1:4000".parse().unwrap(), "127.0.0.1:4001".parse().unwrap());
+        assert!(t.connect(tab, &alice));
+        assert!(!t.connect(other_tab, &alice));
+        assert_eq!(t.players().len(), 1);
+        assert!(t.disconnect(tab).is_none());
+        assert_eq!(t.disconnect(other_tab).map(|p| p.id), Some(alice.id.clone()));
+        assert!(t.players().is_empty());
+        t.connect(tab, &alice);
+        t.connect(other_tab, &alice);
+        assert_eq!(t.disconnect_player(&alice.id).map(|p| p.id), Some(alice.id.clone()));
+        assert!(t.disconnect(tab).is_none());
+        assert!(t.disconnect_player(&alice.id).is_none());
+    }
+}
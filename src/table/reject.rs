@@ -0,0 +1,150 @@
+use rocket::serde::{json::serde_json, Serialize};
+
+use super::{Denied, ElementState, TableState, TableUpdate};
+
+// When an update is rejected, only the player that sent it is told. Clients move elements before
+// the server accepts the move, so the reply includes the element as the server sees it, letting
+// the client undo whatever it changed locally.
+
+/// Why an update was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Reason {
+    /// The player can't access the table anymore, e.g. they were kicked
+    Denied,
+    /// Only the server can send this update
+    ServerOnly,
+    /// The table is locked, and the player isn't the host
+    Locked,
+    NotHost,
+    UnknownElement,
+    /// The icon isn't in the pack, or the pack isn't loaded on the table
+    UnknownIcon,
+    UnknownPack,
+    PackAlreadyLoaded,
+    /// The table already has as many icon packs as it's allowed
+    TooManyPacks,
+    /// The table already has as many elements as it's allowed
+    TooManyElements,
+    /// A new deck or die has more cards or faces than it's allowed
+    TooManyItems,
+    /// Someone else is holding the element, see `grab`
+    Grabbed,
+    /// The chat message is longer than allowed, see `chat`
+    TooLong,
+    /// The player is sending too many messages, see `throttle`
+    Throttled,
+    /// The player kept sending too many messages, so the connection should be closed
+    Disconnected,
+    /// The update doesn't make sense for the table as it is, e.g. drawing from an empty deck
+    Invalid,
+}
+
+impl Reason {
+    fn message(&self) -> &'static str {
+        match self {
+            Self::Denied => "You can't access this table",
+            Self::ServerOnly => "Only the server can send that",
+            Self::Locked => "The table is locked",
+            Self::NotHost => Denied::NotHost.message(),
+            Self::UnknownElement => "That element doesn't exist",
+            Self::UnknownIcon => "That icon isn't in a loaded icon pack",
+            Self::UnknownPack => "That icon pack doesn't exist",
+            Self::PackAlreadyLoaded => "That icon pack is already loaded",
+            Self::TooManyPacks => "The table can't load any more icon packs",
+            Self::TooManyElements => "The table is full",
+            Self::TooManyItems => "That has too many cards or faces",
+            Self::Grabbed => "Someone else is holding that",
+            Self::TooLong => "That message is too long",
+            Self::Throttled => "You're doing that too fast",
+            Self::Disconnected => "You've been disconnected for sending too many messages",
+            Self::Invalid => "That can't be done right now",
+        }
+    }
+}
+
+impl TableUpdate {
+    /// The name of the update, as sent by clients
+    fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.get("t")?.as_str().map(String::from))
+            .unwrap_or_default()
+    }
+
+    /// The element this update changes, if it changes an existing one
+    fn element_id(&self) -> Option<u32> {
+        match self {
+            Self::ElementDelete { id }
+            | Self::Position { id, .. }
+            | Self::Drag { id, .. }
+            | Self::Grab { id }
+            | Self::Release { id }
+            | Self::Flip { id }
+            | Self::Action { id, .. }
+            | Self::ToHand { id } => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl TableState {
+    /// The reply to a player whose update was rejected
+    pub(super) fn rejection(&self, update: &TableUpdate, reason: Reason) -> TableUpdate {
+        let id = update.element_id();
+        let element = id.and_then(|id| {
+            let guard = self.elements.guard();
+            self.elements.get(&id, &guard).cloned()
+        });
+        // Most updates fail without saying why, but the element being gone is the usual reason
+        let reason = match reason {
+            Reason::Invalid if id.is_some() && element.is_none() => Reason::UnknownElement,
+            reason => reason,
+        };
+        TableUpdate::Rejected {
+            update: update.name(),
+            reason,
+            message: reason.message().to_string(),
+            id,
+            element,
+        }
+    }
+
+    /// The reply to a player who can't access the table
+    pub(super) fn denied(update: &TableUpdate) -> TableUpdate {
+        TableUpdate::Rejected {
+            update: update.name(),
+            reason: Reason::Denied,
+            message: Reason::Denied.message().to_string(),
+            id: None,
+            element: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_elements_are_reported() {
+        let t = TableState::tester();
+        let update = TableUpdate::Flip { id: 7 };
+        match t.rejection(&update, Reason::Invalid) {
+            TableUpdate::Rejected {
+                update,
+                reason,
+                id,
+                element,
+                ..
+            } => {
+                assert_eq!(update, "flip");
+                assert_eq!(reason, Reason::UnknownElement);
+                assert_eq!(id, Some(7));
+                assert!(element.is_none());
+            }
+            _ => panic!("expected a rejection"),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::atomic::Ordering;
+
+use chrono::Utc;
+use rocket::{
+    get,
+    serde::{json::serde_json, Deserialize, Serialize},
+    State,
+};
+
+use super::{persist, Denied, ElementState, GlobalState, Player, TableState, TableUpdate};
+use crate::{account::DBConnInst, APIResponse, Error};
+
+// Every update accepted by the websocket is logged, along with the updates the server sent in
+// response. New entries are kept on the table until the next snapshot, which writes them to the
+// `table_log` table. Entries older than `log_retention` are pruned back to the latest full snapshot
+// before then, so replays always have somewhere to start.
+//
+// Replaying the log only reconstructs what players could see. Hidden state, like the cards left
+// in a deck, comes from the most recent full snapshot in the log.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct LogEntry {
+    seq: u64,
+    /// Unix timestamp, in milliseconds
+    at: i64,
+    /// Who made the change, if it was made by a player
+    player: Option<Player>,
+    event: LogEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "e", rename_all = "snake_case")]
+enum LogEvent {
+    /// The complete state of the table, e.g. when it was created
+    Snapshot { table: persist::SavedTable },
+    Update {
+        update: TableUpdate,
+        /// Updates the server sent to everyone in response
+        results: Vec<TableUpdate>,
+    },
+}
+
+impl LogEntry {
+    pub(super) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub(super) fn at(&self) -> i64 {
+        self.at
+    }
+
+    pub(super) fn is_snapshot(&self) -> bool {
+        matches!(self.event, LogEvent::Snapshot { .. })
+    }
+
+    pub(super) fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Log entries are always valid json")
+    }
+}
+
+impl TableState {
+    fn log(&self, player: Option<&Player>, event: LogEvent) {
+        // Holding the lock while taking the next seq keeps the log in order
+        let mut log = self.log.lock().unwrap();
+        log.push(LogEntry {
+            seq: self.log_seq.fetch_add(1, Ordering::AcqRel),
+            at: Utc::now().timestamp_millis(),
+            player: player.cloned(),
+            event,
+        });
+        // The next seq is saved with the table, so it's never reused after a restart
+        self.mark_dirty();
+    }
+
+    /// Logs the complete state of the table, which replays start from
+    pub(super) fn log_snapshot(&self, player: Option<&Player>) {
+        self.log(
+            player,
+            LogEvent::Snapshot {
+                table: persist::SavedTable::from(self),
+            },
+        );
+    }
+
+    pub(super) fn log_update(
+        &self,
+        player: &Player,
+        update: TableUpdate,
+        results: Vec<TableUpdate>,
+    ) {
+        self.log(Some(player), LogEvent::Update { update, results });
+    }
+
+    /// Log entries that haven't been written to the database yet
+    pub(super) fn pending_log(&self) -> Vec<LogEntry> {
+        self.log.lock().unwrap().clone()
+    }
+
+    pub(super) fn take_log(&self) -> Vec<LogEntry> {
+        std::mem::take(&mut *self.log.lock().unwrap())
+    }
+
+    /// Puts back entries that failed to be written
+    pub(super) fn restore_log(&self, mut entries: Vec<LogEntry>) {
+        let mut log = self.log.lock().unwrap();
+        entries.append(&mut log);
+        *log = entries;
+    }
+
+    /// Applies the effects of an update that players saw
+    fn apply_logged(&self, update: &TableUpdate) {
+        let guard = self.elements.guard();
+        match update {
+            TableUpdate::ElementCreate {
+                icon_pack,
+                icon_id,
+                id,
+                top,
+                left,
+            }
+            | TableUpdate::DeckCreate {
+                icon_pack,
+                icon_id,
+                id,
+                top,
+                left,
+                ..
+            }
+            | TableUpdate::CardCreate {
+                icon_pack,
+                icon_id,
+                id,
+                top,
+                left,
+                ..
+            }
+            | TableUpdate::DiceCreate {
+                icon_pack,
+                icon_id,
+                id,
+                top,
+                left,
+                ..
+            } => {
+                self.elements.insert(
+                    *id,
+                    ElementState::new(*id, *icon_pack, *icon_id, *top, *left),
+                    &guard,
+                );
+                self.cur_id.fetch_max(id + 1, Ordering::AcqRel);
+            }
+            TableUpdate::Position { id, top, left } => {
+                if let Some(el) = self.elements.get(id, &guard) {
+                    el.top.store(*top, Ordering::Release);
+                    el.left.store(*left, Ordering::Release);
+                }
+            }
+            TableUpdate::ElementDelete { id } => {
+                self.elements.remove(id, &guard);
+            }
+            TableUpdate::PublicState { id, public_state } => {
+                if let Some(el) = self.elements.get(id, &guard) {
+                    let state_guard = el.public_state.guard();
+                    el.public_state.clear(&state_guard);
+                    for (k, v) in public_state {
+                        el.public_state.insert(k.clone(), v.clone(), &state_guard);
+                    }
+                }
+            }
+            TableUpdate::IconpackLoad { pack } => {
+                let packs_guard = self.icon_packs.guard();
+                self.icon_packs.insert(*pack, &packs_guard);
+            }
+            TableUpdate::Kick { player } => {
+                self.kick(player);
+            }
+            TableUpdate::Lock { locked } => self.set_locked(*locked),
+            TableUpdate::Listed { listed } => self.set_listed(*listed),
+            TableUpdate::TransferHost { player } => {
+                self.transfer_host(player);
+            }
+            TableUpdate::Rename { name } => {
+                self.rename(name);
+            }
+            TableUpdate::Sharing { sharing } => self.set_sharing(sharing.clone()),
+            _ => (),
+        }
+    }
+
+    /// Rebuilds the table as it was at `at`, a unix timestamp in milliseconds
+    fn replay(log: &[LogEntry], at: i64) -> Self {
+        let log: Vec<_> = log.iter().take_while(|e| e.at <= at).collect();
+        let start = log
+            .iter()
+            .rposition(|e| matches!(e.event, LogEvent::Snapshot { .. }));
+        let table = match start.map(|i| &log[i].event) {
+            Some(LogEvent::Snapshot { table }) => TableState::from(table.clone()),
+            _ => TableState::new(
+                String::new(),
+                super::SharingType::Public {},
+                None,
+                flurry::HashSet::new(),
+            ),
+        };
+        for entry in &log[start.map_or(0, |i| i + 1)..] {
+            if let LogEvent::Update { update, results } = &entry.event {
+                if !update.replaced() {
+                    table.apply_logged(update);
+                }
+                for result in results {
+                    table.apply_logged(result);
+                }
+            }
+        }
+        table
+    }
+}
+
+/// Loads the whole log for a table, if the player is its host
+async fn full_log<T>(
+    state: &GlobalState,
+    id: &str,
+    player: &Player,
+    db: &mut DBConnInst,
+) -> Result<Vec<LogEntry>, APIResponse<T>> {
+    // Read the pending entries first, so entries written by a snapshot in between show up in the
+    // database instead of being missed
+    let pending = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) if t.is_host(player) => t.pending_log(),
+            Some(_) => return Err(Denied::NotHost.response()),
+            None => {
+                return Err(APIResponse::not_found(Error {
+                    text: format!("`{}` not found", id),
+                }))
+            }
+        }
+    };
+    let rows = match persist::load_log(db.con(), id).await {
+        Ok(rows) => rows,
+        Err(_e) => {
+            return Err(APIResponse::internal_error(Error {
+                text: format!("Internal Error"),
+            }))
+        }
+    };
+    let mut log: Vec<LogEntry> = rows
+        .iter()
+        .filter_map(|row| serde_json::from_str(row).ok())
+        .collect();
+    let last = log.last().map(|e| e.seq);
+    log.extend(
+        pending
+            .into_iter()
+            .filter(|e| last.map_or(true, |last| e.seq > last)),
+    );
+    Ok(log)
+}
+
+/// Every change made to a table, for the host to download
+#[get("/api/table/<id>/log")]
+pub(super) async fn download_log(
+    id: &str,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<Vec<LogEntry>> {
+    match full_log(state, id, &player, &mut db).await {
+        Ok(log) => APIResponse::ok(log),
+        Err(e) => e,
+    }
+}
+
+/// The table as it was at `at`, a unix timestamp in milliseconds
+#[get("/api/table/<id>/replay?<at>")]
+pub(super) async fn replay(
+    id: &str,
+    at: i64,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<TableState> {
+    match full_log(state, id, &player, &mut db).await {
+        Ok(log) => APIResponse::ok(TableState::replay(&log, at)),
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use rocket::{
+    get, post,
+    serde::{json::Json, Deserialize, Serialize},
+    State,
+};
+
+use super::{
+    add_table, load_icon_pack, persist::SavedTable, Denied, GlobalState, IconPack, Player,
+    PlayerId, SharingType, TableName, TableState,
+};
+use crate::{account::DBConnInst, APIResponse, Error};
+
+/// Version of the save file format, which should be increased whenever a change would stop old
+/// servers from loading new saves
+const SAVE_VERSION: u32 = 1;
+
+/// A complete table, including its hidden state, that can be loaded on any server
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct SaveFile {
+    version: u32,
+    table: SavedTable,
+    /// Every icon pack the table uses. Icon packs are matched by name when importing, since ids
+    /// are different on each server.
+    icon_packs: Vec<SavedIconPack>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SavedIconPack {
+    /// The id used in the saved table
+    id: u32,
+    #[serde(flatten)]
+    pack: IconPack,
+}
+
+#[get("/api/table/<id>/export")]
+pub(super) async fn export_table(
+    id: &str,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<SaveFile> {
+    let table = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) if t.is_host(&player) => {
+                let mut table = SavedTable::from(t);
+                table.hide_password();
+                table
+            }
+            Some(_) => return Denied::NotHost.response(),
+            None => {
+                return APIResponse::not_found(Error {
+                    text: format!("`{}` not found", id),
+                })
+            }
+        }
+    };
+    let mut icon_packs = vec![];
+    for &pack_id in table.icon_packs() {
+        match load_icon_pack(pack_id, &mut db).await {
+            Ok(pack) => icon_packs.push(SavedIconPack { id: pack_id, pack }),
+            Err(_e) => {
+                return APIResponse::internal_error(Error {
+                    text: format!("Internal Error"),
+                })
+            }
+        }
+    }
+    APIResponse::ok(SaveFile {
+        version: SAVE_VERSION,
+        table,
+        icon_packs,
+    })
+}
+
+/// Creates a new table from a save file, hosted by whoever uploaded it
+#[post("/api/table/import", data = "<save>")]
+pub(super) async fn import_table(
+    save: Json<SaveFile>,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<TableName> {
+    let SaveFile {
+        version,
+        mut table,
+        icon_packs,
+    } = save.into_inner();
+    if version > SAVE_VERSION {
+        return APIResponse::bad_request(Error {
+            text: format!("This save was made by a newer version of the server"),
+        });
+    }
+    let host = match &player.id {
+        PlayerId::User(id) => Some(id.clone()),
+        PlayerId::Guest(_) => None,
+    };
+    if matches!(table.sharing(), SharingType::Password { password } if password.is_empty()) {
+        // Passwords aren't exported, so the table is invite only until the host picks a new one.
+        // Guests can't host those, so their tables are public instead.
+        table.set_sharing(match host {
+            Some(_) => SharingType::Whitelist { invite: vec![] },
+            None => SharingType::Public {},
+        });
+    }
+    if matches!(table.sharing(), SharingType::Whitelist { .. }) && host.is_none() {
+        return APIResponse::unauthorized(Error {
+            text: format!("Guests can't create invite only tables"),
+        });
+    }
+    // Checked before looking anything up, so oversized saves are turned away cheaply
+    if let Err(text) = table.sanitize(&state.config) {
+        return APIResponse::bad_request(Error {
+            text: text.to_string(),
+        });
+    }
+    let mut ids = HashMap::new();
+    for saved in icon_packs {
+        match sqlx::query!("SELECT id FROM icon_packs WHERE name = ?", saved.pack.name)
+            .fetch_one(db.con())
+            .await
+        {
+            Ok(row) => {
+                ids.insert(saved.id, row.id);
+            }
+            Err(sqlx::Error::RowNotFound) => {
+                return APIResponse::not_found(Error {
+                    text: format!("Icon pack `{}` isn't available", saved.pack.name),
+                })
+            }
+            Err(_e) => {
+                return APIResponse::internal_error(Error {
+                    text: format!("Internal Error"),
+                })
+            }
+        }
+    }
+    // Every icon has to come from a pack in the save, which is loaded on the table
+    let loaded = table.icon_packs().to_vec();
+    let icons = table.icons();
+    if !loaded.iter().all(|pack| ids.contains_key(pack))
+        || !icons.iter().all(|(pack, _)| loaded.contains(pack))
+    {
+        return APIResponse::bad_request(Error {
+            text: format!("This save uses icon packs it doesn't include"),
+        });
+    }
+    table.remap_icon_packs(&ids);
+    let packs: Vec<_> = ids.values().copied().collect();
+    state.icons.load(&packs, &state.db).await;
+    if !table
+        .icons()
+        .into_iter()
+        .all(|(pack, icon)| state.icons.contains(pack, icon))
+    {
+        return APIResponse::bad_request(Error {
+            text: format!("This save uses icons that don't exist"),
+        });
+    }
+    table.reset(host);
+    let table = TableState::from(table);
+    table.log_snapshot(Some(&player));
+    add_table(state, table, &mut db).await
+}
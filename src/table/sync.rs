@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+
+use rocket::serde::Serialize;
+
+use super::{Player, TableState, TableUpdate, TableView};
+
+// Every update broadcast to a table is given the next version number for that table. The most
+// recent updates are kept, so a client that reconnects can be sent whatever it missed. If it
+// missed too much, it's sent a snapshot of the whole table instead.
+//
+// Versions start again whenever the table is loaded, e.g. after a restart, so each load has a
+// random epoch. A client that last saw another epoch is always sent a snapshot, since whatever it
+// has may not have been saved.
+//
+// Changes made outside the websocket, e.g. through the REST api, can't be broadcast straight away.
+// They're queued, and broadcast along with the next update anyone at the table sends.
+
+/// An update sent to clients, along with the table version it results in
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct Sequenced {
+    /// Only broadcasts have a version, updates sent to a single client don't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<u64>,
+    /// The epoch the version belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<u32>,
+    #[serde(flatten)]
+    update: TableUpdate,
+}
+
+impl From<TableUpdate> for Sequenced {
+    fn from(update: TableUpdate) -> Self {
+        Self {
+            v: None,
+            e: None,
+            update,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Sequencer {
+    /// Picked when the table is loaded, so versions from before then can be told apart
+    epoch: u32,
+    version: u64,
+    recent: VecDeque<Sequenced>,
+    /// Sequenced updates that haven't been broadcast yet
+    queued: Vec<Sequenced>,
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self {
+            epoch: rand::random(),
+            version: 0,
+            recent: VecDeque::new(),
+            queued: vec![],
+        }
+    }
+}
+
+impl Sequencer {
+    fn push(&mut self, update: TableUpdate, limit: usize) -> Sequenced {
+        self.version += 1;
+        let sequenced = Sequenced {
+            v: Some(self.version),
+            e: Some(self.epoch),
+            update,
+        };
+        self.recent.push_back(sequenced.clone());
+        while self.recent.len() > limit {
+            self.recent.pop_front();
+        }
+        sequenced
+    }
+}
+
+impl TableState {
+    /// The version of the table, i.e. the number of updates that have been broadcast
+    pub(super) fn version(&self) -> u64 {
+        self.sequencer.lock().unwrap().version
+    }
+
+    /// Which load of the table the version belongs to
+    pub(super) fn epoch(&self) -> u32 {
+        self.sequencer.lock().unwrap().epoch
+    }
+
+    /// Gives each update the next version, and keeps them in case a client misses them
+    pub(super) fn sequence(&self, updates: Vec<TableUpdate>, limit: usize) -> Vec<Sequenced> {
+        let mut sequencer = self.sequencer.lock().unwrap();
+        updates
+            .into_iter()
+            .map(|update| sequencer.push(update, limit))
+            .collect()
+    }
+
+    /// Sequences updates that can't be broadcast yet, see `take_queued`
+    pub(super) fn queue(&self, updates: Vec<TableUpdate>, limit: usize) {
+        let mut sequencer = self.sequencer.lock().unwrap();
+        for update in updates {
+            let sequenced = sequencer.push(update, limit);
+            sequencer.queued.push(sequenced);
+        }
+    }
+
+    /// Queued updates, which whoever takes them has to broadcast
+    pub(super) fn take_queued(&self) -> Vec<Sequenced> {
+        std::mem::take(&mut self.sequencer.lock().unwrap().queued)
+    }
+
+    /// Updates broadcast after `version` of `epoch`, if they're all still available
+    fn missed(&self, epoch: u32, version: u64) -> Option<Vec<Sequenced>> {
+        let sequencer = self.sequencer.lock().unwrap();
+        if epoch != sequencer.epoch {
+            // The table was reloaded since the client last saw it
+            return None;
+        }
+        if version == sequencer.version {
+            return Some(vec![]);
+        }
+        if version > sequencer.version {
+            return None;
+        }
+        match sequencer.recent.front() {
+            Some(Sequenced { v: Some(oldest), .. }) if version + 1 >= *oldest => Some(
+                sequencer
+                    .recent
+                    .iter()
+                    .filter(|u| u.v > Some(version))
+                    .cloned()
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Brings a client that last saw `version` of `epoch` up to date
+    pub(super) fn resync(&self, epoch: u32, version: u64, player: Player) -> Vec<Sequenced> {
+        let hand = TableUpdate::Hand {
+            cards: self.hand(&player.id),
+        };
+        let mut replies = match self.missed(epoch, version) {
+            Some(missed) => missed,
+            None => vec![TableUpdate::Snapshot {
+                state: Box::new(self.view(player)),
+            }
+            .into()],
+        };
+        replies.push(hand.into());
+        replies
+    }
+
+    /// The table, as seen by `player`. Only what they can see is copied.
+    pub(super) fn view(&self, player: Player) -> TableView {
+        let elements = {
+            let guard = self.elements.guard();
+            self.elements
+                .iter(&guard)
+                .map(|(id, el)| (*id, el.clone()))
+                .collect()
+        };
+        let icon_packs = {
+            let guard = self.icon_packs.guard();
+            self.icon_packs.iter(&guard).copied().collect()
+        };
+        TableView {
+            created: self.created,
+            name: self.name(),
+            locked: self.is_locked(),
+            listed: self.is_listed(),
+            elements,
+            icon_packs,
+            version: self.version(),
+            epoch: self.epoch(),
+            is_host: self.is_host(&player),
+            hand: self.hand(&player.id),
+            hands: self.hand_sizes(),
+            grabs: self.held(),
+            players: self.players(),
+            chat: self.messages(),
+            me: player,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(updates: Option<Vec<Sequenced>>) -> Option<Vec<u64>> {
+        updates.map(|u| u.into_iter().filter_map(|u| u.v).collect())
+    }
+
+    #[test]
+    fn missed_updates() {
+        let t = TableState::tester();
+        let epoch = t.epoch();
+        let lock = || TableUpdate::Lock { locked: true };
+        t.sequence(vec![lock(), lock(), lock(), lock()], 2);
+        assert_eq!(versions(t.missed(epoch, 4)), Some(vec![]));
+        assert_eq!(versions(t.missed(epoch, 3)), Some(vec![4]));
+        assert_eq!(versions(t.missed(epoch, 2)), Some(vec![3, 4]));
+        // Version 2 was forgotten
+        assert_eq!(versions(t.missed(epoch, 1)), None);
+        assert_eq!(versions(t.missed(epoch, 5)), None);
+        // The same version from before the table was loaded isn't the same table
+        assert_eq!(versions(t.missed(epoch.wrapping_add(1), 4)), None);
+        let alice = Player {
+            id: crate::table::PlayerId::Guest("alice".into()),
+            name: "Alice".into(),
+        };
+        let replies = t.resync(epoch.wrapping_add(1), 4, alice);
+        assert!(matches!(replies[0].update, TableUpdate::Snapshot { .. }));
+    }
+}
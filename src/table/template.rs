@@ -0,0 +1,197 @@
+use rocket::{
+    delete, get, post,
+    serde::{
+        json::{serde_json, Json},
+        Deserialize, Serialize,
+    },
+    State,
+};
+
+use super::{persist::SavedTable, Denied, GlobalState, Player, PlayerId, TableState};
+use crate::{account::DBConnInst, APIResponse, Empty, Error};
+
+// Templates are saved layouts of a table, i.e. its elements and icon packs, that new tables can
+// start from. They're stored in the `templates` table and belong to the user that saved them.
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct TemplateInfo {
+    id: u32,
+    name: String,
+}
+
+/// Templates saved by a user, sorted by name
+pub(super) async fn list(
+    user: &str,
+    db: &mut DBConnInst,
+) -> Result<Vec<TemplateInfo>, sqlx::Error> {
+    Ok(sqlx::query!(
+        "SELECT id, name FROM templates WHERE owner = ? ORDER BY name",
+        user
+    )
+    .fetch_all(db.con())
+    .await?
+    .into_iter()
+    .map(|row| TemplateInfo {
+        id: row.id,
+        name: row.name,
+    })
+    .collect())
+}
+
+/// Creates a table from a template, which has to belong to the player
+pub(super) async fn load<T>(
+    id: u32,
+    player: &Player,
+    db: &mut DBConnInst,
+) -> Result<TableState, APIResponse<T>> {
+    let user = match &player.id {
+        PlayerId::User(user) => user,
+        PlayerId::Guest(_) => {
+            return Err(APIResponse::unauthorized(Error {
+                text: format!("You must be logged in to use templates"),
+            }))
+        }
+    };
+    let row = match sqlx::query!(
+        "SELECT state FROM templates WHERE id = ? AND owner = ?",
+        id,
+        user
+    )
+    .fetch_one(db.con())
+    .await
+    {
+        Ok(row) => row,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(APIResponse::not_found(Error {
+                text: format!("Template not found"),
+            }))
+        }
+        Err(_e) => {
+            return Err(APIResponse::internal_error(Error {
+                text: format!("Internal Error"),
+            }))
+        }
+    };
+    match serde_json::from_str::<SavedTable>(&row.state) {
+        Ok(mut saved) => {
+            saved.reset(Some(user.clone()));
+            Ok(saved.into())
+        }
+        Err(e) => {
+            println!("Failed to load template {}: {}", id, e);
+            Err(APIResponse::internal_error(Error {
+                text: format!("Internal Error"),
+            }))
+        }
+    }
+}
+
+#[get("/api/templates")]
+pub(super) async fn templates(
+    player: Player,
+    mut db: DBConnInst,
+) -> APIResponse<Vec<TemplateInfo>> {
+    match &player.id {
+        PlayerId::User(user) => match list(user, &mut db).await {
+            Ok(templates) => APIResponse::ok(templates),
+            Err(_e) => APIResponse::internal_error(Error {
+                text: format!("Internal Error"),
+            }),
+        },
+        PlayerId::Guest(_) => APIResponse::ok(vec![]),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct SaveTemplate<'a> {
+    name: &'a str,
+}
+
+/// Saves the layout of a table as a template. Only the host can do this, since the template
+/// includes hidden state like the cards in a deck.
+#[post("/api/table/<id>/template", data = "<data>")]
+pub(super) async fn save_template(
+    id: &str,
+    data: Json<SaveTemplate<'_>>,
+    player: Player,
+    state: &State<GlobalState>,
+    mut db: DBConnInst,
+) -> APIResponse<TemplateInfo> {
+    let user = match &player.id {
+        PlayerId::User(user) => user,
+        PlayerId::Guest(_) => {
+            return APIResponse::unauthorized(Error {
+                text: format!("You must be logged in to save templates"),
+            })
+        }
+    };
+    let name = data.name.trim();
+    if name.is_empty() {
+        return APIResponse::bad_request(Error {
+            text: format!("Template names can't be empty"),
+        });
+    }
+    let layout = {
+        let guard = state.map.guard();
+        match state.map.get(id, &guard) {
+            Some(t) if t.is_host(&player) => SavedTable::from(t).layout().to_json(),
+            Some(_) => return Denied::NotHost.response(),
+            None => {
+                return APIResponse::not_found(Error {
+                    text: format!("`{}` not found", id),
+                })
+            }
+        }
+    };
+    match sqlx::query!(
+        "INSERT INTO templates (owner, name, state, created) VALUES (?, ?, ?, NOW())",
+        user,
+        name,
+        layout,
+    )
+    .execute(db.con())
+    .await
+    {
+        Ok(res) => APIResponse::ok(TemplateInfo {
+            id: res.last_insert_id() as u32,
+            name: name.to_string(),
+        }),
+        Err(_e) => APIResponse::internal_error(Error {
+            text: format!("Internal Error"),
+        }),
+    }
+}
+
+#[delete("/api/templates/<id>")]
+pub(super) async fn delete_template(
+    id: u32,
+    player: Player,
+    mut db: DBConnInst,
+) -> APIResponse<Empty> {
+    let user = match &player.id {
+        PlayerId::User(user) => user,
+        PlayerId::Guest(_) => {
+            return APIResponse::unauthorized(Error {
+                text: format!("You must be logged in to delete templates"),
+            })
+        }
+    };
+    match sqlx::query!(
+        "DELETE FROM templates WHERE id = ? AND owner = ?",
+        id,
+        user
+    )
+    .execute(db.con())
+    .await
+    {
+        Ok(res) if res.rows_affected() > 0 => APIResponse::ok(Empty {}),
+        Ok(_) => APIResponse::not_found(Error {
+            text: format!("Template not found"),
+        }),
+        Err(_e) => APIResponse::internal_error(Error {
+            text: format!("Internal Error"),
+        }),
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rocket::serde::Deserialize;
+
+use super::{reject::Reason, PlayerId, TableUpdate};
+
+// Messages sent over the table websocket are rate limited with token buckets. Each connection has
+// its own bucket, and so does each player, so opening several connections doesn't get around the
+// limit. A player that keeps sending messages while throttled is disconnected, and their messages
+// are ignored for a while. Their connection is closed, so it can't keep receiving the table.
+
+/// How many messages players can send over the websocket
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct RateLimit {
+    /// Messages per second each connection can send
+    connection_rate: f64,
+    /// Messages each connection can send at once, after not sending any for a while
+    connection_burst: f64,
+    /// Messages per second all of a player's connections can send together
+    player_rate: f64,
+    player_burst: f64,
+    /// Throttled messages a player can send before being disconnected
+    strikes: u32,
+    /// Seconds a disconnected player's messages are ignored for
+    ban: u64,
+    /// How much of a message each drag preview or cursor position counts as. Clients send lots of
+    /// them, but most are dropped anyway, see `drag` and `pointer`.
+    drag_cost: f64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            connection_rate: 20.0,
+            connection_burst: 40.0,
+            player_rate: 30.0,
+            player_burst: 60.0,
+            strikes: 50,
+            ban: 60,
+            drag_cost: 0.25,
+        }
+    }
+}
+
+/// What to do with a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Verdict {
+    Allow,
+    /// Reject the message, telling the player they're sending too many
+    Throttle,
+    /// Reject the message, and tell the player they've been disconnected
+    Disconnect,
+    /// Ignore the message, since the player was disconnected
+    Ignore,
+}
+
+impl Verdict {
+    /// Why the message was rejected, if the player should be told
+    pub(super) fn reason(&self) -> Option<Reason> {
+        match self {
+            Self::Throttle => Some(Reason::Throttled),
+            Self::Disconnect => Some(Reason::Disconnected),
+            Self::Allow | Self::Ignore => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+
+    fn is_full(&self, burst: f64) -> bool {
+        self.tokens >= burst
+    }
+
+    fn has(&self, cost: f64) -> bool {
+        self.tokens >= cost
+    }
+
+    fn take(&mut self, cost: f64) -> bool {
+        if self.has(cost) {
+            self.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PlayerLimit {
+    bucket: Bucket,
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    connections: HashMap<SocketAddr, Bucket>,
+    players: HashMap<PlayerId, PlayerLimit>,
+    pruned: Instant,
+}
+
+#[derive(Debug)]
+pub(super) struct Limiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                connections: HashMap::new(),
+                players: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+}
+
+/// Seconds between removing buckets that aren't in use
+const PRUNE_INTERVAL: u64 = 60;
+
+impl Limiter {
+    /// Uses up a message for the connection and player that sent it
+    pub(super) fn check(
+        &self,
+        addr: SocketAddr,
+        player: &PlayerId,
+        update: &TableUpdate,
+        limit: &RateLimit,
+    ) -> Verdict {
+        let cost = match update {
+            TableUpdate::Drag { .. } | TableUpdate::Cursor { .. } => limit.drag_cost,
+            _ => 1.0,
+        };
+        self.check_at(addr, player, cost, limit, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        addr: SocketAddr,
+        player: &PlayerId,
+        cost: f64,
+        limit: &RateLimit,
+        now: Instant,
+    ) -> Verdict {
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.saturating_duration_since(buckets.pruned) > Duration::from_secs(PRUNE_INTERVAL) {
+            buckets.prune(limit, now);
+        }
+        let Buckets {
+            connections,
+            players,
+            ..
+        } = &mut *buckets;
+        let state = players.entry(player.clone()).or_insert_with(|| PlayerLimit {
+            bucket: Bucket::new(limit.player_burst, now),
+            strikes: 0,
+            banned_until: None,
+        });
+        match state.banned_until {
+            Some(until) if now < until => return Verdict::Ignore,
+            Some(_) => state.banned_until = None,
+            None => (),
+        }
+        state.bucket.refill(limit.player_rate, limit.player_burst, now);
+        if state.bucket.is_full(limit.player_burst) {
+            // The player has slowed down, so they're forgiven
+            state.strikes = 0;
+        }
+        let connection = connections
+            .entry(addr)
+            .or_insert_with(|| Bucket::new(limit.connection_burst, now));
+        connection.refill(limit.connection_rate, limit.connection_burst, now);
+        // Both buckets need a token, so one isn't used up when the other is empty
+        if connection.has(cost) && state.bucket.take(cost) {
+            connection.take(cost);
+            return Verdict::Allow;
+        }
+        state.strikes += 1;
+        if state.strikes > limit.strikes {
+            state.strikes = 0;
+            state.banned_until = Some(now + Duration::from_secs(limit.ban));
+            Verdict::Disconnect
+        } else {
+            Verdict::Throttle
+        }
+    }
+}
+
+impl Limiter {
+    /// Forgets a connection once it's closed. The player's limit is kept, so a disconnected
+    /// player's other connections stay banned.
+    pub(super) fn forget(&self, addr: SocketAddr) {
+        self.buckets.lock().unwrap().connections.remove(&addr);
+    }
+}
+
+impl Buckets {
+    /// Removes buckets that are full, since they're the same as new ones
+    fn prune(&mut self, limit: &RateLimit, now: Instant) {
+        self.connections.retain(|_, b| {
+            b.refill(limit.connection_rate, limit.connection_burst, now);
+            !b.is_full(limit.connection_burst)
+        });
+        self.players.retain(|_, p| {
+            p.bucket.refill(limit.player_rate, limit.player_burst, now);
+            p.banned_until.map_or(false, |until| now < until)
+                || !p.bucket.is_full(limit.player_burst)
+        });
+        self.pruned = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spammers_are_throttled_then_disconnected() {
+        let limit = RateLimit {
+            connection_rate: 1.0,
+            connection_burst: 2.0,
+            player_rate: 1.0,
+            player_burst: 2.0,
+            strikes: 1,
+            ban: 10,
+            drag_cost: 0.5,
+        };
+        let limiter = Limiter::default();
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let player = PlayerId::Guest("spammer".into());
+        let now = Instant::now();
+        let check = |secs| {
+            let at = now + Duration::from_secs(secs);
+            limiter.check_at(addr, &player, 1.0, &limit, at)
+        };
+        assert_eq!(check(0), Verdict::Allow);
+        assert_eq!(check(0), Verdict::Allow);
+        assert_eq!(check(0), Verdict::Throttle);
+        assert_eq!(check(0), Verdict::Disconnect);
+        assert_eq!(check(5), Verdict::Ignore);
+        assert_eq!(check(11), Verdict::Allow);
+    }
+}
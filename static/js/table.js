@@ -8,9 +8,26 @@ let ws = (function() {
     'use strict';
 
     let icon_packs = {};
+    let me = undefined;
+    let is_host = false;
+    let locked = false;
+    let hand = [];
+    let hand_sizes = {};
+    // Everyone connected to the table, by id
+    let players = {};
+    let replaying = false;
+    // Last table version applied, and which load of the table it's from, see `resync`
+    let version = undefined;
+    let epoch = undefined;
+    let resync_pending = false;
+    // Set when the server disconnects us, e.g. for sending too many messages
+    let disconnected = false;
+    // Changes the host makes outside the table page are only sent along with other updates, so
+    // check in every so often
+    const HEARTBEAT_INTERVAL = 15000;
 
-    let table_id = window.location.pathname.replace("/table/", "");
     let top = $("#tabletop");
+    let table_id = top.attr("data-table");
 
     let ws = undefined;
 
@@ -18,8 +35,14 @@ let ws = (function() {
         let ret = new WebSocket("ws://localhost:8000/ws/table/" + table_id);
         ret.addEventListener("open", function(_e) {
             console.log("Connected to server");
+            // Catch up on anything missed while disconnected
+            resync_pending = false;
+            resync();
         });
         ret.addEventListener("close", function(_e) {
+            if (disconnected) {
+                return;
+            }
             console.log("Retrying connection");
             ws = create_ws();
         });
@@ -28,19 +51,149 @@ let ws = (function() {
             console.log(e);
         });
         ret.addEventListener("message", function(e) {
+            if (replaying) {
+                return;
+            }
             let data = JSON.parse(e.data);
-            if (data.t == "position") {
+            if (data.v !== undefined) {
+                if (data.e !== epoch) {
+                    // The table was reloaded, so everything has to be fetched again
+                    resync();
+                    return;
+                } else if (version === undefined || data.v <= version) {
+                    return;
+                } else if (data.v > version + 1) {
+                    // Updates have to be applied in order, so ask for the ones that were missed
+                    resync();
+                    return;
+                }
+                version = data.v;
+                resync_pending = false;
+            }
+            if (data.t == "snapshot") {
+                resync_pending = false;
+                apply_state(data.state);
+            } else if (data.t == "position") {
                 $("#el_" + data.id).css("top", data.top).css("left", data.left);
-            } else if (data.t == "element_create") {
+                set_grabbed(data.id);
+            } else if (data.t == "drag") {
+                // Our own previews come back too, and would fight with the drag
+                let el = $("#el_" + data.id).not(".ui-draggable-dragging");
+                el.css("top", data.top).css("left", data.left);
+                if (el.hasClass("grabbed")) {
+                    // Dragging keeps the element held
+                    set_grabbed(data.id, el.data("grab_player"), el.data("grab_ttl"));
+                }
+            } else if (data.t == "grabbed") {
+                set_grabbed(data.id, data.player, data.expires_in);
+            } else if (data.t == "release") {
+                set_grabbed(data.id);
+            } else if (["element_create", "deck_create", "card_create", "dice_create"].includes(data.t)) {
                 //$("#el_" + data.id).css("top", data.top).css("left", data.left);
+                $("#el_" + data.id).remove();
                 top.append(`<div id="el_${data.id}"
         class="item"
         data-pack="${data.icon_pack}" data-icon="${data.icon_id}"></div>`);
                 let el = $("#el_" + data.id);
                 el_mods(el, data);
                 icon_fill(el, icon_packs[data.icon_pack].icons[data.icon_id]);
+            } else if (data.t == "public_state") {
+                let el = $("#el_" + data.id);
+                el.data("public_state", data.public_state);
+                render_state(el);
+                if (el.hasClass("selected")) {
+                    show_ctx(el);
+                }
+            } else if (data.t == "hand") {
+                hand = data.cards;
+                show_hand();
+            } else if (data.t == "hand_size") {
+                hand_sizes[JSON.stringify(data.player.id)] = data;
+                if (is_me(data.player.id) && data.count != hand.length) {
+                    // Our hand changed in another tab, and only that tab was sent the cards
+                    resync();
+                }
+                show_hand();
+            } else if (data.t == "joined") {
+                players[JSON.stringify(data.player.id)] = data.player;
+                show_players();
+                if (!is_me(data.player.id)) {
+                    notify(`${data.player.name} joined`);
+                }
+            } else if (data.t == "left") {
+                delete players[JSON.stringify(data.player.id)];
+                show_players();
+                $("#" + cursor_id(data.player.id)).remove();
+                notify(`${data.player.name} left`);
+            } else if (data.t == "message") {
+                show_message(data);
+                if (!$("#chatDialog").hasClass("show") && !is_me(data.player.id)) {
+                    notify(`${data.player.name}: ${data.text}`);
+                }
+            } else if (data.t == "delete_message") {
+                $("#msg_" + data.id).remove();
+            } else if (data.t == "cursor_moved") {
+                if (!is_me(data.player.id)) {
+                    show_cursor(data.player, data.top, data.left);
+                }
+            } else if (data.t == "pinged") {
+                show_ping(data.player, data.top, data.left);
+            } else if (data.t == "rolled") {
+                let faces = data.rolls.map(r => describe(r.face));
+                notify(`${data.player.name} rolled ${faces.join(", ")}`);
+            } else if (data.t == "rejected") {
+                if (data.reason == "disconnected" || data.reason == "denied") {
+                    disconnected = true;
+                    ret.close();
+                    alert(data.message);
+                    return;
+                } else if (data.reason != "throttled" || $("#notices .throttled").length == 0) {
+                    notify(data.message, data.reason);
+                }
+                // Dropped previews don't matter, the position is sent again when the drag ends
+                let dropped = data.reason == "throttled" && data.update == "drag";
+                if (data.id !== null && !dropped) {
+                    // Put the element back the way the server has it
+                    let old = $("#el_" + data.id);
+                    let selected = old.hasClass("selected");
+                    let grabbed = old.hasClass("grabbed");
+                    let holder = old.data("grab_player");
+                    let ttl = old.data("grab_ttl");
+                    old.remove();
+                    if (data.element !== null) {
+                        show_element(data.id, data.element);
+                        if (grabbed) {
+                            set_grabbed(data.id, holder, ttl);
+                        }
+                        if (selected) {
+                            $("#el_" + data.id).addClass("selected");
+                            show_ctx($("#el_" + data.id));
+                        }
+                    } else if (selected) {
+                        hide_ctx();
+                    }
+                }
             } else if (data.t == "element_delete") {
                 $("#el_" + data.id).remove();
+            } else if (data.t == "lock") {
+                set_locked(data.locked);
+            } else if (data.t == "listed") {
+                $("#listed").prop("checked", data.listed);
+            } else if (data.t == "rename") {
+                set_name(data.name);
+            } else if (data.t == "transfer_host") {
+                set_host(is_me(data.player));
+            } else if (data.t == "deleted") {
+                disconnected = true;
+                alert("The host has deleted this table");
+                window.location.pathname = "/find";
+            } else if (data.t == "kick") {
+                if (is_me(data.player)) {
+                    alert("You have been removed from this table");
+                    window.location.pathname = "/find";
+                }
+            } else if (data.t == "sharing") {
+                // Nothing to update
             } else {
                 console.log("TODO: " + data.t);
             }
@@ -48,20 +201,418 @@ let ws = (function() {
         return ret;
     }
     ws = create_ws();
+    setInterval(resync, HEARTBEAT_INTERVAL);
 
-    function icon_fill(el, icon) {
+    function resync() {
+        if (version === undefined || resync_pending || ws.readyState !== WebSocket.OPEN) {
+            return;
+        }
+        resync_pending = true;
+        ws.send(JSON.stringify({
+            t: "resync",
+            version: version,
+            epoch: epoch,
+        }));
+        // Allow asking again if the server had nothing to send
+        setTimeout(() => resync_pending = false, 2000);
+    }
+
+    function is_me(player) {
+        return me !== undefined && player.t === me.id.t && player.id === me.id.id;
+    }
+
+    function set_name(name) {
+        document.title = name;
+        $("#tableName").val(name);
+    }
+
+    function set_host(host) {
+        is_host = host;
+        if (is_host) {
+            $("#hostSettings").removeClass("d-none");
+            $.getJSON("/api/table/" + table_id + "/whitelist").then(show_whitelist);
+        } else {
+            $("#hostSettings").addClass("d-none");
+        }
+        $(".delete-message").toggleClass("d-none", !is_host);
+        set_locked(locked);
+    }
+
+    function set_locked(l) {
+        locked = l;
+        $("#locked").prop("checked", locked);
+        let disabled = locked && !is_host;
+        $(".item").not(".grabbed").draggable("option", "disabled", disabled);
+        if (disabled) {
+            $(".edit-icon").addClass("d-none");
+        } else {
+            $(".edit-icon").removeClass("d-none");
+        }
+    }
+
+    $("#rename").on("click", function() {
+        ws.send(JSON.stringify({
+            t: "rename",
+            name: $("#tableName").val(),
+        }));
+    });
+    $("#locked").on("change", function() {
+        ws.send(JSON.stringify({
+            t: "lock",
+            locked: $(this).prop("checked"),
+        }));
+    });
+    $("#listed").on("change", function() {
+        ws.send(JSON.stringify({
+            t: "listed",
+            listed: $(this).prop("checked"),
+        }));
+    });
+    $("#sharing").on("change", function() {
+        if ($(this).val() == "password") {
+            $("#password").removeClass("d-none");
+        } else {
+            $("#password").addClass("d-none");
+        }
+    });
+    $("#sharingSave").on("click", function() {
+        let sharing = {};
+        if ($("#sharing").val() == "password") {
+            sharing.password = $("#password").val();
+        } else if ($("#sharing").val() == "whitelist") {
+            sharing.invite = [];
+        }
+        ws.send(JSON.stringify({
+            t: "sharing",
+            sharing: sharing,
+        }));
+    });
+    function show_whitelist(names) {
+        let list = $("#whitelist");
+        list.html('');
+        for (let name of names) {
+            let item = $(`<li class="list-group-item d-flex justify-content-between align-items-center"></li>`);
+            item.text(name);
+            let remove = $(`<button type="button" class="btn-close" aria-label="Remove"></button>`);
+            remove.on("click", function() {
+                $.ajax({
+                    url: "/api/table/" + table_id + "/whitelist/" + encodeURIComponent(name),
+                    method: "DELETE",
+                }).then(show_whitelist);
+            });
+            list.append(item.append(remove));
+        }
+    }
+    $("#invite").on("click", function() {
+        $.post("/api/table/" + table_id + "/whitelist", JSON.stringify({
+            username: $("#inviteName").val(),
+        })).then(function(names) {
+            $("#inviteName").val('');
+            show_whitelist(names);
+        }, function(e) {
+            alert(e.responseJSON.text);
+        });
+    });
+    $("#saveTemplate").on("click", function() {
+        $.post("/api/table/" + table_id + "/template", JSON.stringify({
+            name: $("#templateName").val(),
+        })).then(function(template) {
+            $("#templateName").val('');
+            notify(`Saved template ${template.name}`);
+        }, function(e) {
+            alert(e.responseJSON.text);
+        });
+    });
+    $("#setNickname").on("click", function() {
+        $.post("/api/nickname", JSON.stringify({
+            nickname: $("#nickname").val(),
+        })).then(function() {
+            // Everyone else sees the new name once we've joined again
+            window.location.reload();
+        }, function(e) {
+            alert(e.responseJSON.text);
+        });
+    });
+    $("#transferHost").on("click", function() {
+        $.post("/api/table/" + table_id + "/host", JSON.stringify({
+            username: $("#newHost").val(),
+        })).then(function() {
+            set_host(false);
+        }, function(e) {
+            alert(e.responseJSON.text);
+        });
+    });
+
+    function icon_html(icon) {
         if (icon.t === "image") {
-            el.html('<img src="' + icon.src + '" alt="' + icon.name + '" />')
+            return '<img src="' + icon.src + '" alt="' + icon.name + '" />';
         } else if (icon.t === "icon") {
-            el.html('<i src="' + icon.class + '" alt="' + icon.name + '"></i>')
+            return '<i src="' + icon.class + '" alt="' + icon.name + '"></i>';
         } else if (icon.t === "svg") {
-            el.html('<img src="' + icon.src + '" alt="' + icon.name + '" />')
+            return '<img src="' + icon.src + '" alt="' + icon.name + '" />';
         } else {
             console.log("Todo: " + icon.t);
+            return '';
+        }
+    }
+
+    function icon_fill(el, icon) {
+        el.html(icon_html(icon));
+        el.each(function() {
+            render_state($(this));
+        });
+    }
+
+    function find_icon(pack, id) {
+        if (icon_packs[pack] === undefined) {
+            return undefined;
+        }
+        return icon_packs[pack].icons[id];
+    }
+
+    function render_state(el) {
+        el.children(".el-state").remove();
+        let state = el.data("public_state") || {};
+        if (state.face === undefined && state.face_up !== undefined) {
+            // Face down cards show their back, which is the element's own icon
+            let icon = find_icon(el.attr("data-pack"), el.attr("data-icon"));
+            if (icon !== undefined) {
+                el.html(icon_html(icon));
+            }
+        } else if (state.face !== undefined) {
+            let face = state.face.Single;
+            if (face.Icon !== undefined) {
+                // Dice show the face they landed on instead of their own icon
+                let icon = find_icon(face.Icon.icon_pack, face.Icon.icon_id);
+                if (icon !== undefined) {
+                    el.html(icon_html(icon));
+                }
+            } else {
+                let text = face.Num !== undefined ? face.Num : face.Str;
+                el.html('<span class="el-face fs-2"></span>');
+                el.children(".el-face").text(text);
+            }
+        }
+        if (state.count !== undefined) {
+            el.append(`<span class="el-state badge rounded-pill bg-dark position-absolute top-0 start-100 translate-middle">${state.count.Single.Num}</span>`);
+        }
+    }
+
+    function is_deck(el) {
+        let state = el.data("public_state") || {};
+        return state.count !== undefined;
+    }
+
+    function is_dice(el) {
+        let state = el.data("public_state") || {};
+        return state.face !== undefined && state.face_up === undefined;
+    }
+
+    function is_card(el) {
+        let state = el.data("public_state") || {};
+        return state.face_up !== undefined;
+    }
+
+    function show_ctx(el) {
+        $(".ctx-icon.d-none").removeClass("d-none");
+        if (is_deck(el)) {
+            $(".deck-icon").removeClass("d-none");
+        } else {
+            $(".deck-icon").addClass("d-none");
+        }
+        if ($(".item.selected").toArray().some(e => is_card($(e)))) {
+            $(".card-icon").removeClass("d-none");
+        } else {
+            $(".card-icon").addClass("d-none");
+        }
+        if ($(".item.selected").toArray().some(e => is_dice($(e)))) {
+            $(".dice-icon").removeClass("d-none");
+        } else {
+            $(".dice-icon").addClass("d-none");
+        }
+    }
+
+    function hide_ctx() {
+        $(".ctx-icon").addClass("d-none");
+        $(".deck-icon").addClass("d-none");
+        $(".dice-icon").addClass("d-none");
+        $(".card-icon").addClass("d-none");
+    }
+
+    function show_hand() {
+        let list = $("#hand");
+        list.html('');
+        hand.forEach(function(card, index) {
+            let item = $(`<button type="button" class="btn btn-light hand-card me-1"></button>`);
+            let face = card.face.Icon !== undefined ? find_icon(card.face.Icon.icon_pack, card.face.Icon.icon_id) : undefined;
+            if (face !== undefined) {
+                item.html(icon_html(face));
+            } else {
+                item.text(describe(card.face));
+            }
+            // Shift plays the card face down
+            item.on("click", function(e) {
+                ws.send(JSON.stringify({
+                    t: "from_hand",
+                    index: index,
+                    top: 100,
+                    left: 100,
+                    face_up: !e.shiftKey,
+                }));
+            });
+            list.append(item);
+        });
+        let others = $("#handSizes");
+        others.html('');
+        for (let key in hand_sizes) {
+            let size = hand_sizes[key];
+            if (!is_me(size.player.id) && size.count > 0) {
+                let item = $(`<span class="badge bg-secondary me-1"></span>`);
+                item.text(`${size.player.name}: ${size.count}`);
+                others.append(item);
+            }
+        }
+    }
+
+    function show_players() {
+        let list = $("#players");
+        list.html('');
+        for (let key in players) {
+            let item = $(`<span class="badge me-1"></span>`);
+            item.addClass(is_me(players[key].id) ? "bg-primary" : "bg-secondary");
+            item.text(players[key].name);
+            list.append(item);
+        }
+    }
+
+    // Each player gets their own colour, the same for everyone at the table
+    function player_colour(id) {
+        let hash = 0;
+        for (let c of JSON.stringify(id)) {
+            hash = (hash * 31 + c.charCodeAt(0)) % 360;
+        }
+        return `hsl(${hash}, 70%, 45%)`;
+    }
+
+    function cursor_id(id) {
+        return `cursor_${id.t}_${id.id}`;
+    }
+
+    function show_cursor(player, y, x) {
+        let cursor = $("#" + cursor_id(player.id));
+        if (cursor.length == 0) {
+            cursor = $(`<div class="cursor"><span class="badge"></span></div>`);
+            cursor.attr("id", cursor_id(player.id));
+            cursor.css("border-color", player_colour(player.id));
+            cursor.find(".badge").css("background", player_colour(player.id)).text(player.name);
+            top.append(cursor);
+        }
+        cursor.css({ top: y, left: x });
+    }
+
+    function show_ping(player, y, x) {
+        let ping = $(`<div class="ping"></div>`);
+        ping.css({ top: y, left: x, "border-color": player_colour(player.id) });
+        ping.attr("title", player.name);
+        top.append(ping);
+        setTimeout(() => ping.remove(), 1500);
+    }
+
+    // Where the mouse is on the table
+    function table_pos(e) {
+        let offset = top.offset();
+        return {
+            top: Math.max(0, Math.round(e.pageY - offset.top)),
+            left: Math.max(0, Math.round(e.pageX - offset.left)),
+        };
+    }
+
+    const CURSOR_INTERVAL = 1000 / 15;
+    let last_cursor = 0;
+    // Where the cursor moved since it was last sent, which is sent once the interval is over
+    let cursor_timer = undefined;
+    let pending_cursor = undefined;
+    function send_cursor() {
+        cursor_timer = undefined;
+        if (pending_cursor === undefined || ws === undefined || ws.readyState != 1) {
+            return;
         }
+        last_cursor = Date.now();
+        ws.send(JSON.stringify({ t: "cursor", ...pending_cursor }));
+        pending_cursor = undefined;
+    }
+    top.on("mousemove", function(e) {
+        pending_cursor = table_pos(e);
+        let wait = CURSOR_INTERVAL - (Date.now() - last_cursor);
+        if (wait <= 0) {
+            send_cursor();
+        } else if (cursor_timer === undefined) {
+            cursor_timer = setTimeout(send_cursor, wait);
+        }
+    });
+    // Double clicking an empty part of the table pings it
+    top.on("dblclick", function(e) {
+        if (e.target === this) {
+            ws.send(JSON.stringify({ t: "ping", ...table_pos(e) }));
+        }
+    });
+
+    function show_message(message) {
+        let item = $(`<li class="list-group-item"><strong></strong> <span></span></li>`);
+        item.attr("id", "msg_" + message.id);
+        item.attr("title", new Date(message.sent).toLocaleString());
+        item.find("strong").css("color", player_colour(message.player.id)).text(message.player.name);
+        item.find("span").text(message.text);
+        // Only the host can delete messages
+        let remove = $(`<button type="button" class="btn-close float-end delete-message" aria-label="Delete"></button>`);
+        remove.toggleClass("d-none", !is_host);
+        remove.on("click", function() {
+            ws.send(JSON.stringify({
+                t: "delete_message",
+                id: message.id,
+            }));
+        });
+        item.append(remove);
+        let chat = $("#chat");
+        chat.append(item);
+        chat.scrollTop(chat[0].scrollHeight);
+    }
+
+    $("#chatForm").on("submit", function(e) {
+        e.preventDefault();
+        let text = $("#chatText").val().trim();
+        if (text === "") {
+            return;
+        }
+        ws.send(JSON.stringify({
+            t: "chat",
+            text: text,
+        }));
+        $("#chatText").val("");
+    });
+
+    function notify(text, cls) {
+        let note = $(`<div class="alert alert-info py-1 mb-1"></div>`);
+        note.addClass(cls || "");
+        note.text(text);
+        $("#notices").append(note);
+        setTimeout(() => note.remove(), 5000);
+    }
+
+    function describe(item) {
+        if (item.Icon !== undefined) {
+            let icon = find_icon(item.Icon.icon_pack, item.Icon.icon_id);
+            return icon !== undefined ? icon.name : "?";
+        }
+        return item.Num !== undefined ? item.Num : item.Str;
     }
 
     function load_pack(pack) {
+        // Placeholder, so the pack isn't loaded twice
+        icon_packs[pack] = {
+            name: "",
+            icons: {},
+        };
         $.getJSON("/api/icons/" + pack).then(function(data) {
             $("#addDialogMenu").append(`
 <div class="accordion-item" id="pack_p_${pack}">
@@ -85,6 +636,52 @@ let ws = (function() {
                 icons: {},
             };
             let icon_buttons = $(`#pack_a_${pack}`);
+            icon_buttons.append(`
+<button class="btn btn-secondary" id="deck_${pack}">Add as deck</button>
+`);
+            icon_buttons.append(`
+<button class="btn btn-secondary" id="dice_${pack}">Add as dice</button>
+`);
+            $(`#dice_${pack}`).on("click", function() {
+                let icons = Object.values(icon_packs[pack].icons);
+                if (icons.length == 0) {
+                    return;
+                }
+                ws.send(JSON.stringify({
+                    t: "dice_create",
+                    icon_pack: pack,
+                    icon_id: icons[0].id,
+                    id: 0,
+                    top: 0,
+                    left: 0,
+                    faces: icons.map(icon => ({
+                        Icon: {
+                            icon_pack: pack,
+                            icon_id: icon.id,
+                        },
+                    })),
+                }));
+            });
+            $(`#deck_${pack}`).on("click", function() {
+                let icons = Object.values(icon_packs[pack].icons);
+                if (icons.length == 0) {
+                    return;
+                }
+                ws.send(JSON.stringify({
+                    t: "deck_create",
+                    icon_pack: pack,
+                    icon_id: icons[0].id,
+                    id: 0,
+                    top: 0,
+                    left: 0,
+                    cards: icons.map(icon => ({
+                        Icon: {
+                            icon_pack: pack,
+                            icon_id: icon.id,
+                        },
+                    })),
+                }));
+            });
             for (let i in data.icons) {
                 let icon = data.icons[i];
                 icon_packs[pack].icons[icon.id] = icon;
@@ -105,8 +702,67 @@ let ws = (function() {
                     ws.send(data);
                 });
             }
+            show_hand();
         });
     }
+    $(".deck-icon").on("click", function() {
+        let act = $(this).attr("data-act");
+        $(".item.selected").each(function() {
+            ws.send(JSON.stringify({
+                t: "action",
+                id: +this.id.split('_')[1],
+                act: act,
+            }));
+        });
+    });
+    function undo() {
+        ws.send(JSON.stringify({
+            t: "undo",
+        }));
+    }
+    function redo() {
+        ws.send(JSON.stringify({
+            t: "redo",
+        }));
+    }
+    $("#undo").on("click", undo);
+    $("#redo").on("click", redo);
+    $(document).on("keydown", function(e) {
+        if (!(e.ctrlKey || e.metaKey) || $(e.target).is("input")) {
+            return;
+        }
+        if (e.key == "z" && !e.shiftKey) {
+            e.preventDefault();
+            undo();
+        } else if (e.key == "y" || (e.key == "Z" && e.shiftKey)) {
+            e.preventDefault();
+            redo();
+        }
+    });
+    $("#toHand").on("click", function() {
+        $(".item.selected").each(function() {
+            ws.send(JSON.stringify({
+                t: "to_hand",
+                id: +this.id.split('_')[1],
+            }));
+        });
+    });
+    $("#flip").on("click", function() {
+        $(".item.selected").each(function() {
+            if (is_card($(this))) {
+                ws.send(JSON.stringify({
+                    t: "flip",
+                    id: +this.id.split('_')[1],
+                }));
+            }
+        });
+    });
+    $("#roll").on("click", function() {
+        ws.send(JSON.stringify({
+            t: "roll",
+            ids: $(".item.selected").toArray().map(e => +e.id.split('_')[1]),
+        }));
+    });
     $("#delete").on("click", function() {
         $(".item.selected").each(function() {
             let json = JSON.stringify({
@@ -119,15 +775,23 @@ let ws = (function() {
 
     function el_mods(el, data) {
         el.draggable(drag_settings);
+        el.draggable("option", "disabled", locked && !is_host);
         el.on("contextmenu", function(e) {
                 e.preventDefault();
                 if (el.hasClass("selected")) {
                     el.removeClass("selected");
-                    $(".ctx-icon").addClass("d-none");
+                    if ($(".item.selected").length == 0) {
+                        hide_ctx();
+                    } else {
+                        show_ctx($(".item.selected").first());
+                    }
                 } else {
-                    $(".item.selected").removeClass("selected");
+                    // Shift adds to the selection, e.g. to roll several dice at once
+                    if (!e.shiftKey) {
+                        $(".item.selected").removeClass("selected");
+                    }
                     el.addClass("selected");
-                    $(".ctx-icon.d-none").removeClass("d-none");
+                    show_ctx(el);
                 }
             })
             .css('top', data.top)
@@ -136,8 +800,30 @@ let ws = (function() {
             .css('display', 'block');
     }
 
+    // Milliseconds between drag previews, the server only sends others `drag_rate` a second
+    const DRAG_INTERVAL = 1000 / 30;
+    let last_drag = 0;
     let drag_settings = {
         containment: 'parent',
+        start: function(e, _u) {
+            ws.send(JSON.stringify({
+                t: "grab",
+                id: +e.target.id.split('_')[1],
+            }));
+        },
+        drag: function(e, u) {
+            let now = Date.now();
+            if (now - last_drag < DRAG_INTERVAL) {
+                return;
+            }
+            last_drag = now;
+            ws.send(JSON.stringify({
+                t: "drag",
+                id: +e.target.id.split('_')[1],
+                top: u.position.top,
+                left: u.position.left,
+            }));
+        },
         stop: function(e, u) {
             let json = JSON.stringify({
                 t: "position",
@@ -148,17 +834,103 @@ let ws = (function() {
             ws.send(json);
         }
     };
-    $.getJSON("/api/table/" + table_id + "/state").then(function(data) {
+    // Elements someone else is holding can't be dragged until they let go, or the grab expires
+    function set_grabbed(id, player, expires_in) {
+        let el = $("#el_" + id);
+        clearTimeout(el.data("grab_timer"));
+        if (player === undefined || is_me(player.id)) {
+            el.removeClass("grabbed opacity-50").removeAttr("title");
+            el.draggable("option", "disabled", locked && !is_host);
+            return;
+        }
+        el.addClass("grabbed opacity-50").attr("title", `${player.name} is holding this`);
+        el.draggable("option", "disabled", true);
+        el.data("grab_player", player);
+        el.data("grab_ttl", expires_in);
+        el.data("grab_timer", setTimeout(() => set_grabbed(id), expires_in));
+    }
+
+    function show_element(id, element) {
+        top.append(
+            '<div id="el_' + id + '" class="item" data-pack="' + element.icon_pack + '" data-icon="' + element.icon_id + '"></div>');
+        let el = $("#el_" + id);
+        el.data("public_state", element.public_state);
+        el_mods(el, element);
+        let icon = find_icon(element.icon_pack, element.icon_id);
+        if (icon !== undefined) {
+            icon_fill(el, icon);
+        }
+    }
+
+    function show_elements(elements) {
         top.html('');
-        for (let id in data.elements) {
-            top.append(
-                '<div id="el_' + id + '" class="item" data-pack="' + data.elements[id].icon_pack + '" data-icon="' + data.elements[id].icon_id + '"></div>');
-            el_mods($("#el_" + id), data.elements[id]);
+        for (let id in elements) {
+            show_element(id, elements[id]);
+        }
+    }
+
+    $("#downloadLog").attr("href", "/api/table/" + table_id + "/log");
+    $("#exportTable").attr("href", "/api/table/" + table_id + "/export");
+    $("#replay").on("click", function() {
+        let at = new Date($("#replayAt").val()).getTime();
+        if (isNaN(at)) {
+            return;
+        }
+        $.getJSON("/api/table/" + table_id + "/replay?at=" + at).then(function(data) {
+            // Live updates are ignored until the page is reloaded
+            replaying = true;
+            hide_ctx();
+            show_elements(data.elements);
+            $(".item").draggable("option", "disabled", true);
+            $(".edit-icon").addClass("d-none");
+            $("#replayLive").removeClass("d-none");
+        }, function(e) {
+            alert(e.responseJSON.text);
+        });
+    });
+    $("#replayLive").on("click", function() {
+        window.location.reload();
+    });
+
+    function apply_state(data) {
+        version = data.version;
+        epoch = data.epoch;
+        me = data.me;
+        // Only guests can pick their name, users are shown with their username
+        $("#nicknameSettings").toggleClass("d-none", me.id.t !== "guest");
+        $("#nickname").val(me.name);
+        locked = data.locked;
+        hand = data.hand;
+        for (let size of data.hands) {
+            hand_sizes[JSON.stringify(size.player.id)] = size;
+        }
+        players = {};
+        for (let player of data.players) {
+            players[JSON.stringify(player.id)] = player;
+        }
+        show_players();
+        set_name(data.name);
+        $("#chat").html('');
+        $("#listed").prop("checked", data.listed);
+        set_host(data.is_host);
+        show_elements(data.elements);
+        for (let message of data.chat) {
+            show_message(message);
+        }
+        for (let held of data.grabs) {
+            set_grabbed(held.id, held.player, held.expires_in);
         }
         for (let pack of data.icon_packs) {
-            load_pack(pack);
+            if (icon_packs[pack] === undefined) {
+                load_pack(pack);
+            }
         }
+        show_hand();
+    }
 
+    $.getJSON("/api/table/" + table_id + "/state").then(function(data) {
+        apply_state(data);
+        resync();
     });
     return ws;
 })();